@@ -0,0 +1,24 @@
+//! Feeds arbitrary bytes to every decoder of the crate. Malformed input must
+//! be rejected with an error, never panic or allocate more than it holds.
+//!
+//! ```
+//! cargo install cargo-fuzz
+//! cargo +nightly fuzz run deserialize fuzz/corpus/deserialize
+//! ```
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate avalanche;
+
+use avalanche::net::read_frame;
+use avalanche::{MessageLog, NetworkSnapshot, Transaction};
+
+fuzz_target!(|data: &[u8]| {
+    // The encoding is canonical, whatever decodes encodes to the same bytes.
+    if let Ok(tx) = Transaction::deserialize(data) {
+        assert_eq!(tx.serialize(), data);
+    }
+    let _ = MessageLog::read_from(&mut &data[..]);
+    let _ = NetworkSnapshot::read_from(&mut &data[..]);
+    let _ = read_frame(&mut &data[..]);
+});
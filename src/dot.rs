@@ -0,0 +1,41 @@
+//! Graphviz export of the colors nodes hold for a transaction, see
+//! `Network::to_dot`.
+
+use std::fmt::Write;
+
+use {lock_node, Hash, Network, Status};
+
+/// Render every node colored by its status for `hash`, final nodes drawn with
+/// a double border. Edges point from a node to the peers that responded to
+/// its query of the current round.
+pub(crate) fn to_dot(net: &Network, hash: &Hash) -> String {
+    let mut out = String::new();
+    writeln!(out, "digraph avalanche {{").unwrap();
+    writeln!(out, "    label=\"{}\";", hash).unwrap();
+    writeln!(out, "    node [style=filled];").unwrap();
+    let mut edges = Vec::new();
+    for (id, node) in net.nodes().iter() {
+        let node = lock_node(node);
+        let state = node.mempool.get(hash);
+        let color = match state.map(|state| &state.status) {
+            Some(Status::Valid) => "green",
+            Some(Status::Invalid) => "red",
+            Some(Status::Unknown) => "gray",
+            None => "white",
+        };
+        let peripheries = if state.is_some_and(|state| state.is_final) { 2 } else { 1 };
+        writeln!(
+            out,
+            "    {} [fillcolor={}, peripheries={}];",
+            id, color, peripheries
+        ).unwrap();
+        if let Some(state) = state {
+            edges.extend(state.responses.keys().map(|peer| (*id, *peer)));
+        }
+    }
+    for (from, to) in edges {
+        writeln!(out, "    {} -> {};", from, to).unwrap();
+    }
+    out.push_str("}\n");
+    out
+}
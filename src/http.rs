@@ -0,0 +1,192 @@
+//! Tiny HTTP endpoint to watch a running simulation, enabled with the `http`
+//! feature. Every `GET` is answered with JSON:
+//!
+//! - `/nodes`: every node with its behavior, stake and mempool size.
+//! - `/tx/{hash}`: the view of every node that knows the transaction.
+//! - `/stats`: the summary of `Network::stats`.
+//!
+//! The server has no thread of its own, the simulation answers waiting
+//! requests by calling `StatusServer::poll` now and then.
+
+use std::fmt::Write as FmtWrite;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use {lock_node, Hash, Network, NetworkStats, Result};
+
+/// Time a client gets to send its request before it is dropped.
+const READ_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Answers status requests for a network, see the module documentation.
+#[derive(Debug)]
+pub struct StatusServer {
+    listener: TcpListener,
+}
+
+impl StatusServer {
+    /// Listen on `addr`, e.g. `127.0.0.1:8080`.
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        Ok(StatusServer { listener })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Answer every request waiting for the server without blocking.
+    /// Returns the number of answered requests.
+    pub fn poll(&self, net: &Network) -> Result<usize> {
+        let mut answered = 0;
+        loop {
+            match self.listener.accept() {
+                Ok((stream, peer)) => {
+                    // A misbehaving client doesn't stop the simulation.
+                    if let Err(err) = answer(stream, net) {
+                        warn!("http: failed to answer {}: {}", peer, err);
+                    }
+                    answered += 1;
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(answered),
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+}
+
+/// Read a single request from `stream` and write the response.
+fn answer(mut stream: TcpStream, net: &Network) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // Headers are not used, but read so the client sees a clean close.
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let mut parts = request.split_whitespace();
+    let (code, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some(path)) => route(net, path),
+        _ => (405, error("only GET is supported")),
+    };
+    debug!("http: {} -> {}", request.trim_end(), code);
+    write!(
+        stream,
+        "HTTP/1.0 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        code,
+        reason(code),
+        body.len(),
+        body
+    )
+}
+
+fn route(net: &Network, path: &str) -> (u16, String) {
+    match path {
+        "/nodes" => (200, nodes_json(net)),
+        "/stats" => (200, stats_json(&net.stats())),
+        _ => match path.strip_prefix("/tx/").map(Hash::from_hex) {
+            Some(Ok(hash)) => tx_json(net, &hash),
+            Some(Err(err)) => (400, error(&err.to_string())),
+            None => (404, error("not found")),
+        },
+    }
+}
+
+fn reason(code: u16) -> &'static str {
+    match code {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        _ => "",
+    }
+}
+
+fn error(msg: &str) -> String {
+    format!("{{\"error\":{:?}}}", msg)
+}
+
+fn nodes_json(net: &Network) -> String {
+    let mut out = String::from("[");
+    for (i, (id, node)) in net.nodes().iter().enumerate() {
+        let node = lock_node(node);
+        if i > 0 {
+            out.push(',');
+        }
+        write!(
+            out,
+            "{{\"id\":{},\"behavior\":\"{:?}\",\"stake\":{},\"mempool\":{},\"failed\":{}}}",
+            id,
+            node.behavior,
+            node.stake,
+            node.mempool.len(),
+            node.failed
+        ).unwrap();
+    }
+    out.push(']');
+    out
+}
+
+/// The view of every node on the transaction, 404 if no node knows it.
+fn tx_json(net: &Network, hash: &Hash) -> (u16, String) {
+    let mut views = String::new();
+    for (id, node) in net.nodes().iter() {
+        let confidence = match lock_node(node).query_confidence(hash) {
+            Some(confidence) => confidence,
+            None => continue,
+        };
+        if !views.is_empty() {
+            views.push(',');
+        }
+        write!(
+            views,
+            "{{\"id\":{},\"status\":\"{:?}\",\"epoch\":{},\"cnt\":{},\"is_final\":{},\
+             \"is_expired\":{}}}",
+            id,
+            confidence.status,
+            confidence.epoch,
+            confidence.cnt,
+            confidence.is_final,
+            confidence.is_expired
+        ).unwrap();
+    }
+    if views.is_empty() {
+        return (404, error("unknown transaction"));
+    }
+    let decision = net
+        .network_decision(hash)
+        .map_or("null".to_string(), |s| format!("\"{:?}\"", s));
+    let body = format!(
+        "{{\"hash\":\"{}\",\"decision\":{},\"nodes\":[{}]}}",
+        hash, decision, views
+    );
+    (200, body)
+}
+
+fn stats_json(stats: &NetworkStats) -> String {
+    let mempools = stats
+        .mempool_sizes
+        .iter()
+        .map(|(id, len)| format!("\"{}\":{}", id, len))
+        .collect::<Vec<_>>()
+        .join(",");
+    format!(
+        "{{\"queries\":{},\"responses\":{},\"dropped_messages\":{},\"finalized\":{},\
+         \"avg_epochs\":{},\"mempool_sizes\":{{{}}},\"safety_violations\":{}}}",
+        stats.messages.queries,
+        stats.messages.responses,
+        stats.dropped_messages,
+        stats.finalized,
+        stats
+            .avg_epochs
+            .map_or("null".to_string(), |epochs| epochs.to_string()),
+        mempools,
+        stats.safety_violations.len()
+    )
+}
@@ -0,0 +1,5139 @@
+extern crate byteorder;
+extern crate hex;
+extern crate rand;
+extern crate ring;
+extern crate untrusted;
+
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
+use rand::{prng::XorShiftRng, seq, thread_rng, Rng, RngCore, SeedableRng};
+use ring::{digest, signature};
+
+mod dot;
+#[macro_use]
+pub mod log;
+#[cfg(feature = "http")]
+pub mod http;
+#[cfg(feature = "net")]
+pub mod net;
+mod report;
+mod snapshot;
+mod sweep;
+
+pub use report::{SimulationReport, TxReport};
+pub use snapshot::NetworkSnapshot;
+pub use sweep::{sweep, SweepGrid, SweepRow, SweepTable};
+
+use std::any::Any;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap, HashSet, VecDeque};
+use std::env;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::ops::Bound;
+use std::panic::{self, AssertUnwindSafe};
+use std::slice;
+use std::sync::{atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering as AtomicOrdering},
+                mpsc::{channel, sync_channel, Receiver, RecvTimeoutError, SendError, Sender,
+                       SyncSender, TryRecvError, TrySendError},
+                Arc,
+                Mutex,
+                MutexGuard,
+                OnceLock,
+                PoisonError,
+                RwLock,
+                RwLockReadGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Random number generator driving the simulation.
+pub type SimRng = XorShiftRng;
+
+/// Environment variable holding the seed of a reproducible simulation.
+pub const SEED_ENV: &str = "AVALANCHE_SEED";
+
+/// Create a simulation rng from `seed`. The same seed always yields the same
+/// sequence of random numbers.
+pub fn seeded_rng(seed: u64) -> SimRng {
+    let mut buf = [0u8; 16];
+    LittleEndian::write_u64(&mut buf[..8], seed);
+    LittleEndian::write_u64(&mut buf[8..], !seed);
+    SimRng::from_seed(buf)
+}
+
+/// Returns the seed set through `AVALANCHE_SEED`, or a random seed if the
+/// variable is not set.
+pub fn default_seed() -> u64 {
+    env::var(SEED_ENV)
+        .ok()
+        .and_then(|seed| seed.parse().ok())
+        .unwrap_or_else(|| {
+            let mut rng = thread_rng();
+            (u64::from(rng.next_u32()) << 32) | u64::from(rng.next_u32())
+        })
+}
+
+/// Hash identifying a transaction, computed with the `HashAlgorithm` of the
+/// transaction. The wire, log and snapshot formats carry its 32 raw bytes,
+/// the hex encoding of `Display` is only meant for humans.
+#[derive(Eq, PartialEq, Clone, Hash)]
+pub struct Hash([u8; 32]);
+
+impl Hash {
+    /// Create a hash from its 32 raw bytes.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Hash> {
+        if bytes.len() != 32 {
+            return Err(AvalancheError::InvalidHash(format!(
+                "expected 32 bytes, got {}",
+                bytes.len()
+            )));
+        }
+        let mut hash = [0; 32];
+        hash.copy_from_slice(bytes);
+        Ok(Hash(hash))
+    }
+
+    /// Create a hash from the hex encoding its `Display` implementation
+    /// prints.
+    pub fn from_hex(s: &str) -> Result<Hash> {
+        let bytes = hex::decode(s).map_err(|err| AvalancheError::InvalidHash(err.to_string()))?;
+        Hash::from_bytes(&bytes)
+    }
+
+    /// The 32 raw bytes `from_bytes` reads back, half the size of the hex
+    /// encoding. The binary encoders write these.
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl From<Hash> for [u8; 32] {
+    fn from(hash: Hash) -> Self {
+        hash.0
+    }
+}
+
+impl From<digest::Digest> for Hash {
+    /// Panics if the digest is not 32 bytes long.
+    fn from(digest: digest::Digest) -> Self {
+        let mut hash = [0; 32];
+        hash.copy_from_slice(digest.as_ref());
+        Hash(hash)
+    }
+}
+
+impl ::std::fmt::Display for Hash {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+impl ::std::fmt::Debug for Hash {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "{:?}", self.to_string())
+    }
+}
+
+/// Digest a transaction is hashed with. It is part of the transaction, so
+/// every node derives the same id. Every algorithm has a 256 bit output to
+/// fit a `Hash`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HashAlgorithm {
+    #[default]
+    Sha256,
+    /// SHA-512 truncated to 256 bits, faster than SHA-256 on 64 bit CPUs.
+    Sha512_256,
+}
+
+impl HashAlgorithm {
+    pub fn digest(self, data: &[u8]) -> Hash {
+        let algorithm = match self {
+            HashAlgorithm::Sha256 => &digest::SHA256,
+            HashAlgorithm::Sha512_256 => &digest::SHA512_256,
+        };
+        Hash::from(digest::digest(algorithm, data))
+    }
+
+    fn write_to<W: Write>(self, w: &mut W) -> io::Result<()> {
+        w.write_u8(match self {
+            HashAlgorithm::Sha256 => 0,
+            HashAlgorithm::Sha512_256 => 1,
+        })
+    }
+
+    fn read_from<R: Read>(r: &mut R) -> io::Result<HashAlgorithm> {
+        match r.read_u8()? {
+            0 => Ok(HashAlgorithm::Sha256),
+            1 => Ok(HashAlgorithm::Sha512_256),
+            _ => Err(invalid_data("invalid hash algorithm")),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Message {
+    Query(QueryMessage),
+    QueryResponse((u64, QueryResponse)),
+    Transaction(Transaction),
+    /// Queries for several transactions sent to the same sample at once.
+    QueryBatch(QueryBatch),
+    /// The responses to a `QueryBatch`, in the order of its queries.
+    QueryBatchResponse((u64, Vec<QueryResponse>)),
+    /// Several new transactions handed to a node at once, which it queries
+    /// in a single batch.
+    Transactions(Vec<Transaction>),
+    /// Sent instead of a response by a node that already finalized the
+    /// queried transaction, see `ConsensusParams::share_decisions`.
+    Decided((u64, Hash, Status)),
+    /// Sent by a node joining the network to a single sampled peer, which
+    /// answers with a `MempoolDigest`.
+    MempoolRequest,
+    /// The transactions the sender is still deciding on.
+    MempoolDigest((u64, Vec<Hash>)),
+    /// Asks for the transactions of a digest the sender does not know.
+    Pull((u64, Vec<Hash>)),
+    /// The transactions asked for with a `Pull`, which the receiver verifies
+    /// and queries like new transactions.
+    Pulled((u64, Vec<Transaction>)),
+}
+
+/// A message sent by node `origin`. Messages are numbered in the order they
+/// were sent over the whole network, so `seq` is unique and increasing.
+#[derive(Debug, Clone)]
+pub struct Envelope {
+    pub seq: u64,
+    pub origin: u64,
+    pub msg: Message,
+}
+
+/// Errors that can occur while nodes handle messages.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AvalancheError {
+    /// A message refers to a transaction the node does not know.
+    UnknownTransaction(Hash),
+    /// A message is addressed to a node that is not part of the network.
+    UnknownNode(u64),
+    /// A node panicked while handling a message and failed, or its mutex got
+    /// poisoned by a panic elsewhere.
+    Poisoned(u64),
+    /// A message could not be sent because the network is gone.
+    Disconnected,
+    /// Two nodes finalized the same transaction to different statuses.
+    SafetyViolation(SafetyViolation),
+    /// Bytes or hex that do not encode a 32 byte hash.
+    InvalidHash(String),
+    /// The dispatcher thread panicked with the given message, no more
+    /// messages get delivered.
+    DispatcherPanicked(String),
+    /// Transactions were not finalized within the given time.
+    Timeout(Duration),
+    /// A message could not be sent or received over a socket, see `net`.
+    Transport(String),
+}
+
+impl ::std::fmt::Display for AvalancheError {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        match self {
+            AvalancheError::UnknownTransaction(hash) => write!(f, "unknown transaction {}", hash),
+            AvalancheError::UnknownNode(id) => write!(f, "unknown node {}", id),
+            AvalancheError::Poisoned(id) => write!(f, "node {} panicked", id),
+            AvalancheError::Disconnected => write!(f, "network is disconnected"),
+            AvalancheError::SafetyViolation(v) => write!(
+                f,
+                "tx {} finalized {:?} by node {} but {:?} by node {}",
+                v.hash, v.first.1, v.first.0, v.conflicting.1, v.conflicting.0
+            ),
+            AvalancheError::InvalidHash(reason) => write!(f, "invalid hash: {}", reason),
+            AvalancheError::DispatcherPanicked(msg) => write!(f, "dispatcher panicked: {}", msg),
+            AvalancheError::Timeout(timeout) => write!(f, "not finalized within {:?}", timeout),
+            AvalancheError::Transport(err) => write!(f, "transport error: {}", err),
+        }
+    }
+}
+
+impl ::std::error::Error for AvalancheError {}
+
+impl<T> From<SendError<T>> for AvalancheError {
+    fn from(_: SendError<T>) -> Self {
+        AvalancheError::Disconnected
+    }
+}
+
+impl From<io::Error> for AvalancheError {
+    fn from(err: io::Error) -> Self {
+        AvalancheError::Transport(err.to_string())
+    }
+}
+
+pub type Result<T = ()> = ::std::result::Result<T, AvalancheError>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Status {
+    Valid,
+    Invalid,
+    /// Uncolored, the node has no preference yet. Responses with this status
+    /// never count towards a quorum.
+    Unknown,
+}
+
+impl Status {
+    /// Returns the opposite status. An uncolored status stays uncolored.
+    pub fn flip(&self) -> Status {
+        match self {
+            Status::Valid => Status::Invalid,
+            Status::Invalid => Status::Valid,
+            Status::Unknown => Status::Unknown,
+        }
+    }
+}
+
+/// How a node takes part in the protocol. Every behavior other than `Honest`
+/// is byzantine and answers queries and verifies transactions regardless of
+/// the actual state.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Behavior {
+    /// Follow the protocol.
+    Honest,
+    /// Consider every transaction valid.
+    AlwaysValid,
+    /// Consider every transaction invalid.
+    AlwaysInvalid,
+    /// Answer with a random status.
+    Random,
+    /// Answer with the opposite of the honest status.
+    Flip,
+}
+
+/// Why a node considered a transaction invalid when it first saw it, see
+/// `TxState::invalid_reason`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InvalidReason {
+    /// The data is not below the limit of `DataLimit`.
+    DataOverLimit,
+    /// The signature does not match the contents of the transaction.
+    BadSignature,
+    /// The node already preferred a conflicting transaction.
+    Conflict,
+    /// The node rejects one of the parents of the transaction.
+    InvalidParent,
+    /// A custom `Validator` rejected the transaction.
+    Validator,
+}
+
+#[derive(Debug, Clone)]
+pub struct QueryResponse {
+    hash: Hash,
+    status: Status,
+    /// Stake of the responding node.
+    stake: u64,
+    /// Total stake of the sample the query was sent to.
+    sampled_stake: u64,
+    /// Progress of the responding node, see `ConsensusParams::diagnostics`.
+    progress: Option<Progress>,
+    /// Round of the query this responds to, echoed from the query.
+    round: u32,
+}
+
+impl QueryResponse {
+    pub fn new(hash: Hash, status: Status, stake: u64, sampled_stake: u64) -> Self {
+        QueryResponse {
+            hash,
+            status,
+            stake,
+            sampled_stake,
+            progress: None,
+            round: 0,
+        }
+    }
+
+    /// Respond to the query of the given round, 0 unless set.
+    pub fn with_round(mut self, round: u32) -> Self {
+        self.round = round;
+        self
+    }
+
+    /// Report the progress of the responding node along with its color.
+    pub fn with_progress(mut self, progress: Progress) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+}
+
+/// Where the responding node is at with the queried transaction, to diagnose
+/// nodes lagging behind or stuck in a partition.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Progress {
+    pub epoch: u32,
+    pub cnt: u32,
+}
+
+/// A response a node received for a transaction, see `Node::vote_history`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Vote {
+    pub responder: u64,
+    pub status: Status,
+    /// Epoch of the receiving node when the response arrived.
+    pub epoch: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct QueryMessage {
+    tx: Transaction,
+    status: Status,
+    /// Number of peers the dispatcher should sample for this query.
+    samples: usize,
+    /// Peers that must not be sampled, e.g. because they already responded.
+    exclude: Vec<u64>,
+    /// Total stake of the sampled peers, filled in by the dispatcher.
+    sampled_stake: u64,
+    /// Round of the querying node the responses count for.
+    round: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct QueryBatch {
+    /// Every queried transaction with our color and the round of the query.
+    queries: Vec<(Transaction, Status, u32)>,
+    /// Number of peers the dispatcher should sample for this batch.
+    samples: usize,
+    /// Total stake of the sampled peers, filled in by the dispatcher.
+    sampled_stake: u64,
+}
+
+impl QueryBatch {
+    pub fn new(queries: Vec<(Transaction, Status, u32)>, samples: usize) -> Self {
+        QueryBatch {
+            queries,
+            samples,
+            sampled_stake: 0,
+        }
+    }
+}
+
+/// Identifies the resource (e.g. an UTXO or account) a transaction spends.
+/// Transactions sharing the same conflict id conflict with each other and at
+/// most one of them can be accepted.
+pub type ConflictId = u64;
+
+pub use ring::signature::Ed25519KeyPair;
+
+/// Create the Ed25519 key pair for the given 32 byte seed.
+pub fn keypair_from_seed(seed: &[u8; 32]) -> Ed25519KeyPair {
+    Ed25519KeyPair::from_seed_unchecked(untrusted::Input::from(seed)).unwrap()
+}
+
+/// Ed25519 signature of a transaction and the public key it verifies with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Signature {
+    pub public_key: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+/// Transactions with data below this limit are valid by default.
+pub const VALID_DATA_LIMIT: i32 = 7;
+
+/// Random transactions carry data in `[0, DATA_RANGE)`, with the default
+/// limit 70% of them are valid.
+pub const DATA_RANGE: i32 = 10;
+
+/// Random transactions get a priority in `[0, PRIORITY_RANGE)`.
+pub const PRIORITY_RANGE: u32 = 100;
+
+#[derive(Debug, Clone)]
+pub struct Transaction {
+    pub nonce: u64,
+    pub conflict_id: ConflictId,
+    /// numbers < `VALID_DATA_LIMIT` are consired valid transactions. Rest is
+    /// invalid.
+    pub data: i32,
+    /// Fee the sender offers, nodes with a full mempool evict transactions
+    /// with a lower priority first.
+    pub priority: u32,
+    /// Transactions this transaction builds on in the DAG. A vote for a
+    /// transaction is implicitly a vote for all of its ancestors.
+    pub parents: Vec<Hash>,
+    /// Signature over all other fields, unsigned transactions are accepted.
+    pub signature: Option<Signature>,
+    pub hash_algorithm: HashAlgorithm,
+    /// The hash of the transaction once `id` computed it.
+    id: OnceLock<Hash>,
+}
+
+/// Transactions are equal if all their fields are, whether or not their hash
+/// is cached yet.
+impl PartialEq for Transaction {
+    fn eq(&self, other: &Transaction) -> bool {
+        self.nonce == other.nonce
+            && self.conflict_id == other.conflict_id
+            && self.data == other.data
+            && self.priority == other.priority
+            && self.parents == other.parents
+            && self.signature == other.signature
+            && self.hash_algorithm == other.hash_algorithm
+    }
+}
+
+/// The genesis transaction, built once with its hash cached, so every copy
+/// shares the hash.
+static GENESIS: OnceLock<Transaction> = OnceLock::new();
+/// The hash of the genesis, so `is_genesis` is a plain comparison.
+static GENESIS_ID: OnceLock<Hash> = OnceLock::new();
+
+impl Transaction {
+    /// The root of the DAG, with a fixed hash. Every node knows it as final
+    /// from the start, so transactions can reference it as their parent.
+    pub fn genesis() -> Self {
+        let genesis = GENESIS.get_or_init(|| {
+            let genesis = Transaction {
+                nonce: 0,
+                conflict_id: 0,
+                data: 0,
+                priority: 0,
+                parents: Vec::new(),
+                signature: None,
+                hash_algorithm: HashAlgorithm::default(),
+                id: OnceLock::new(),
+            };
+            genesis.id();
+            genesis
+        });
+        genesis.clone()
+    }
+
+    pub fn is_genesis(&self) -> bool {
+        self.id() == GENESIS_ID.get_or_init(|| Transaction::genesis().id().clone())
+    }
+
+    /// Create a random transaction that does not conflict with any other.
+    pub fn random() -> Self {
+        Transaction::random_from(&mut SimRng::from_rng(thread_rng()).unwrap())
+    }
+
+    /// Create a random transaction carrying `data`, signed with `keypair`.
+    pub fn signed(keypair: &Ed25519KeyPair, data: i32) -> Self {
+        let mut tx = Transaction::random();
+        tx.data = data;
+        tx.sign(keypair)
+    }
+
+    /// Sign the transaction with the given key pair, replacing any previous
+    /// signature.
+    pub fn sign(mut self, keypair: &Ed25519KeyPair) -> Self {
+        self.id = OnceLock::new();
+        let sig = keypair.sign(&self.payload());
+        self.signature = Some(Signature {
+            public_key: keypair.public_key_bytes().to_vec(),
+            signature: sig.as_ref().to_vec(),
+        });
+        self
+    }
+
+    /// Returns true if the transaction is signed and the signature matches
+    /// its contents.
+    pub fn verify_signature(&self) -> bool {
+        let sig = match self.signature {
+            Some(ref sig) => sig,
+            None => return false,
+        };
+        signature::verify(
+            &signature::ED25519,
+            untrusted::Input::from(&sig.public_key),
+            untrusted::Input::from(&self.payload()),
+            untrusted::Input::from(&sig.signature),
+        ).is_ok()
+    }
+
+    /// Like `random` but draws from the given rng.
+    pub fn random_from<R: Rng + ?Sized>(rng: &mut R) -> Self {
+        let nonce = rng.gen::<u64>();
+        Transaction {
+            nonce,
+            conflict_id: nonce,
+            data: rng.gen_range(0, DATA_RANGE),
+            priority: rng.gen_range(0, PRIORITY_RANGE),
+            parents: Vec::new(),
+            signature: None,
+            hash_algorithm: HashAlgorithm::default(),
+            id: OnceLock::new(),
+        }
+    }
+
+    /// Give the transaction the given priority.
+    pub fn with_priority(mut self, priority: u32) -> Self {
+        self.id = OnceLock::new();
+        self.priority = priority;
+        self
+    }
+
+    /// Hash the transaction with `algorithm` instead of SHA-256. Other nodes
+    /// do the same, the algorithm is sent along with the transaction.
+    pub fn with_hash_algorithm(mut self, algorithm: HashAlgorithm) -> Self {
+        self.id = OnceLock::new();
+        self.hash_algorithm = algorithm;
+        self
+    }
+
+    /// Make the transaction a child of the given parents.
+    pub fn with_parents(mut self, parents: Vec<Hash>) -> Self {
+        self.id = OnceLock::new();
+        self.parents = parents;
+        self
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut buf = vec![];
+        self.write_to(&mut buf).unwrap();
+        buf
+    }
+
+    /// Decode a transaction encoded by `serialize`.
+    pub fn deserialize(buf: &[u8]) -> io::Result<Transaction> {
+        let mut r = buf;
+        let tx = Transaction::read_from(&mut r)?;
+        if !r.is_empty() {
+            return Err(invalid_data("trailing bytes after transaction"));
+        }
+        Ok(tx)
+    }
+
+    /// The signed part of the transaction, everything but the signature.
+    fn payload(&self) -> Vec<u8> {
+        let mut buf = vec![];
+        self.write_payload(&mut buf).unwrap();
+        buf
+    }
+
+    fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        self.write_payload(w)?;
+        match self.signature {
+            Some(ref sig) => {
+                w.write_u8(1)?;
+                write_bytes(w, &sig.public_key)?;
+                write_bytes(w, &sig.signature)
+            }
+            None => w.write_u8(0),
+        }
+    }
+
+    fn write_payload<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_u64::<LittleEndian>(self.nonce)?;
+        w.write_u64::<LittleEndian>(self.conflict_id)?;
+        w.write_i32::<LittleEndian>(self.data)?;
+        w.write_u32::<LittleEndian>(self.priority)?;
+        w.write_u32::<LittleEndian>(self.parents.len() as u32)?;
+        for parent in &self.parents {
+            w.write_all(parent.as_bytes())?;
+        }
+        self.hash_algorithm.write_to(w)
+    }
+
+    fn read_from<R: Read>(r: &mut R) -> io::Result<Transaction> {
+        let nonce = r.read_u64::<LittleEndian>()?;
+        let conflict_id = r.read_u64::<LittleEndian>()?;
+        let data = r.read_i32::<LittleEndian>()?;
+        let priority = r.read_u32::<LittleEndian>()?;
+        let n = r.read_u32::<LittleEndian>()?;
+        let mut parents = Vec::new();
+        for _ in 0..n {
+            parents.push(read_hash(r)?);
+        }
+        let hash_algorithm = HashAlgorithm::read_from(r)?;
+        let signature = match r.read_u8()? {
+            0 => None,
+            1 => Some(Signature {
+                public_key: read_bytes(r)?,
+                signature: read_bytes(r)?,
+            }),
+            _ => return Err(invalid_data("invalid signature flag")),
+        };
+        Ok(Transaction {
+            nonce,
+            conflict_id,
+            data,
+            priority,
+            parents,
+            signature,
+            hash_algorithm,
+            id: OnceLock::new(),
+        })
+    }
+
+    pub fn hash(&self) -> Hash {
+        self.hash_algorithm.digest(&self.serialize())
+    }
+
+    /// Like `hash` but only computed once, clones share the cached hash.
+    /// Changes to the fields of the transaction after the first call are not
+    /// reflected, use `hash` for a transaction that is still being modified.
+    pub fn id(&self) -> &Hash {
+        self.id.get_or_init(|| self.hash())
+    }
+}
+
+/// Generates random transactions at a fixed rate. Iterating the generator
+/// blocks between transactions to keep up the configured rate, and ends after
+/// `count` transactions if a count is set.
+#[derive(Debug, Clone)]
+pub struct TransactionGenerator {
+    interval: Duration,
+    remaining: Option<u64>,
+    last: Option<Instant>,
+    /// Fraction of generated transactions that are valid by default, if set.
+    valid_ratio: Option<f64>,
+    rng: SimRng,
+    clock: Arc<dyn Clock>,
+}
+
+impl TransactionGenerator {
+    /// Create a generator producing `rate` transactions per second forever.
+    pub fn new(rate: u32) -> Self {
+        TransactionGenerator {
+            interval: Duration::from_secs(1) / rate.max(1),
+            remaining: None,
+            last: None,
+            valid_ratio: None,
+            rng: seeded_rng(default_seed()),
+            clock: system_clock(),
+        }
+    }
+
+    /// Generate the transactions from the given seed.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.rng = seeded_rng(seed);
+        self
+    }
+
+    /// Keep up the rate on the given clock. A `MockClock` never blocks, so
+    /// the generator runs as fast as it is iterated.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Stop the generator after `count` transactions.
+    pub fn count(mut self, count: u64) -> Self {
+        self.remaining = Some(count);
+        self
+    }
+
+    /// Make each generated transaction valid under the default rules with
+    /// probability `ratio`, instead of the ratio implied by `DATA_RANGE`.
+    /// The ratio is clamped to `[0, 1]`, NaN keeps the implied ratio.
+    pub fn valid_ratio(mut self, ratio: f64) -> Self {
+        self.valid_ratio = match ratio.is_nan() {
+            true => None,
+            false => Some(ratio.clamp(0.0, 1.0)),
+        };
+        self
+    }
+}
+
+impl Iterator for TransactionGenerator {
+    type Item = Transaction;
+
+    fn next(&mut self) -> Option<Transaction> {
+        if let Some(remaining) = self.remaining {
+            if remaining == 0 {
+                return None;
+            }
+            self.remaining = Some(remaining - 1);
+        }
+        if let Some(last) = self.last {
+            let elapsed = self.clock.now().saturating_duration_since(last);
+            if elapsed < self.interval {
+                self.clock.sleep(self.interval - elapsed);
+            }
+        }
+        self.last = Some(self.clock.now());
+        let mut tx = Transaction::random_from(&mut self.rng);
+        if let Some(ratio) = self.valid_ratio {
+            tx.data = match self.rng.gen_bool(ratio) {
+                true => self.rng.gen_range(0, VALID_DATA_LIMIT),
+                false => self.rng.gen_range(VALID_DATA_LIMIT, DATA_RANGE),
+            };
+        }
+        Some(tx)
+    }
+}
+
+/// Default tuning parameters for the algorithm.
+pub const SAMPLES: usize = 4;
+pub const MAX_EPOCHS: u32 = 4;
+pub const TRESHOLD: f32 = 0.75;
+pub const CONVICTION_TRESHOLD: f32 = 0.75;
+/// Fraction of the nodes that must have finalized a transaction to the same
+/// status for the network to have decided on it, see
+/// `Network::network_decision`.
+pub const DECISION_QUORUM: f64 = 2.0 / 3.0;
+/// Time after which an outstanding query is considered timed out and the
+/// missing responses are re-sampled from the remaining nodes.
+pub const QUERY_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// The decision algorithm nodes run for every transaction.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConsensusMode {
+    /// Flip the preference on every quorum for the other color and reset the
+    /// conviction counter, without keeping any confidence. Quorums that
+    /// alternate between the colors keep the node flipping forever.
+    Snowflake,
+    /// Only flip the preference once the other color gathered more quorums
+    /// than the current one over the lifetime of the transaction. Alternating
+    /// quorums never flip the node, so its conviction keeps growing. Ties
+    /// are broken to a color derived from the hash, the same on every node.
+    Snowball,
+}
+
+/// How a node colors a transaction it first learns about from a query.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorPolicy {
+    /// Adopt the color of the query as in the paper, only uncolored queries
+    /// are verified locally.
+    Adopt,
+    /// Always verify the transaction locally and ignore the color of the
+    /// query. Nodes only change their color through quorums, so a wrongly
+    /// colored query can't spread before the network agreed on it.
+    Verify,
+}
+
+/// Tuning parameters of the Snowball/Avalanche algorithm.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConsensusParams {
+    /// Number of nodes sampled per query.
+    pub k: usize,
+    /// Fraction of `k` responses that must agree on a color to form a quorum,
+    /// rounded up to whole responses, see `required_quorum`.
+    pub alpha: f32,
+    /// Fraction of `k` consecutive quorums needed to accept a color for the epoch.
+    pub conviction: f32,
+    /// Number of accepted epochs after which a transaction is final.
+    pub beta: u32,
+    pub mode: ConsensusMode,
+    /// Maximum number of transactions a node keeps in its mempool, unbounded
+    /// if `None`, not counting the genesis. Finalized transactions are
+    /// evicted first, then those with the lowest priority.
+    pub mempool_capacity: Option<usize>,
+    /// Maximum number of messages in flight, unbounded if `None`. Messages
+    /// sent while the network is saturated are dropped.
+    pub channel_capacity: Option<usize>,
+    /// Time after which a transaction that is still undecided expires and is
+    /// no longer queried, never if `None`.
+    pub ttl: Option<Duration>,
+    /// Let nodes send their progress along with every response, and log the
+    /// epochs of the responders of every round at debug level.
+    pub diagnostics: bool,
+    /// Let nodes answer queries for transactions they finalized with their
+    /// decision, which the querying node adopts right away instead of
+    /// finishing its own rounds. This trusts a single finalized peer, so
+    /// byzantine nodes can break safety with it.
+    pub share_decisions: bool,
+    /// Let nodes keep every response they receive in order, to reconstruct
+    /// why they decided as they did, see `Node::vote_history`.
+    pub vote_history: bool,
+    /// How nodes color transactions they learn about from queries.
+    pub initial_color: ColorPolicy,
+}
+
+impl ConsensusParams {
+    /// Number of agreeing responses needed for a quorum in a sample of `k`
+    /// nodes, which is `alpha * k` rounded up: with `alpha = 0.5` and `k = 4`
+    /// two responses are a quorum, with `k = 5` it takes three.
+    pub fn required_quorum(&self, k: usize) -> usize {
+        self.quorum(k as u64) as usize
+    }
+
+    /// Stake of agreeing responses needed for a quorum in a sample with the
+    /// given total stake. With a stake of 1 per node this is `alpha * k`,
+    /// rounded up the same way as `required_quorum`.
+    fn quorum(&self, sampled_stake: u64) -> u64 {
+        // Don't let rounding errors of the product push an exact quorum up.
+        (f64::from(self.alpha) * sampled_stake as f64 - 1e-6).ceil().max(0.0) as u64
+    }
+
+    /// Number of consecutive quorums needed to accept a color for the epoch.
+    fn conviction_treshold(&self) -> u32 {
+        (self.conviction * self.k as f32) as u32
+    }
+}
+
+impl Default for ConsensusParams {
+    fn default() -> Self {
+        ConsensusParams {
+            k: SAMPLES,
+            alpha: TRESHOLD,
+            conviction: CONVICTION_TRESHOLD,
+            beta: MAX_EPOCHS,
+            mode: ConsensusMode::Snowball,
+            mempool_capacity: None,
+            channel_capacity: None,
+            ttl: None,
+            diagnostics: false,
+            share_decisions: false,
+            vote_history: false,
+            initial_color: ColorPolicy::Adopt,
+        }
+    }
+}
+
+/// The sending end of the channel nodes pass their messages to the network
+/// with. A bounded channel drops messages instead of blocking when it is
+/// full, since nodes send from the dispatcher that drains the channel.
+#[derive(Debug, Clone)]
+struct Outbox {
+    sender: OutboxSender,
+    dropped: Arc<AtomicUsize>,
+    /// Sequence number of the next message, shared by every node.
+    seq: Arc<AtomicU64>,
+}
+
+#[derive(Debug, Clone)]
+enum OutboxSender {
+    Unbounded(Sender<Envelope>),
+    Bounded(SyncSender<Envelope>),
+}
+
+impl Outbox {
+    fn bounded(sender: SyncSender<Envelope>) -> Self {
+        Outbox {
+            sender: OutboxSender::Bounded(sender),
+            dropped: Arc::default(),
+            seq: Arc::default(),
+        }
+    }
+
+    /// Number the message of node `from` as the next one sent.
+    fn envelope(&self, from: u64, msg: Message) -> Envelope {
+        Envelope {
+            seq: self.next_seq(),
+            origin: from,
+            msg,
+        }
+    }
+
+    fn next_seq(&self) -> u64 {
+        self.seq.fetch_add(1, AtomicOrdering::Relaxed)
+    }
+
+    /// Send a message of node `from`. Dropping a message because the channel
+    /// is full is not an error.
+    fn send(&self, from: u64, msg: Message) -> Result {
+        let envelope = self.envelope(from, msg);
+        match self.sender {
+            OutboxSender::Unbounded(ref sender) => Ok(sender.send(envelope)?),
+            OutboxSender::Bounded(ref sender) => match sender.try_send(envelope) {
+                Ok(()) => Ok(()),
+                Err(TrySendError::Full(_)) => {
+                    debug!("node={} network saturated, dropping message", from);
+                    self.dropped.fetch_add(1, AtomicOrdering::Relaxed);
+                    Ok(())
+                }
+                Err(TrySendError::Disconnected(_)) => Err(AvalancheError::Disconnected),
+            },
+        }
+    }
+
+    /// Number of messages dropped because the channel was full.
+    fn dropped(&self) -> usize {
+        self.dropped.load(AtomicOrdering::Relaxed)
+    }
+}
+
+impl From<Sender<Envelope>> for Outbox {
+    fn from(sender: Sender<Envelope>) -> Self {
+        Outbox {
+            sender: OutboxSender::Unbounded(sender),
+            dropped: Arc::default(),
+            seq: Arc::default(),
+        }
+    }
+}
+
+/// Configures a network with chained setters, see `Network::builder`. Every
+/// setting left out keeps the default of `Network::new`.
+#[derive(Debug, Clone)]
+pub struct NetworkBuilder {
+    nodes: u64,
+    params: ConsensusParams,
+    seed: Option<u64>,
+    latency: Latency,
+    topology: Topology,
+    sampling: Option<Arc<dyn SamplingStrategy>>,
+    byzantine: Option<(f32, Behavior)>,
+    validator: Option<Arc<dyn Validator>>,
+    clock: Option<Arc<dyn Clock>>,
+    record: bool,
+}
+
+impl Default for NetworkBuilder {
+    fn default() -> Self {
+        NetworkBuilder {
+            nodes: 10,
+            params: ConsensusParams::default(),
+            seed: None,
+            latency: Latency::default(),
+            topology: Topology::default(),
+            sampling: None,
+            byzantine: None,
+            validator: None,
+            clock: None,
+            record: false,
+        }
+    }
+}
+
+impl NetworkBuilder {
+    /// Number of nodes in the network, 10 by default.
+    pub fn nodes(mut self, n: u64) -> Self {
+        self.nodes = n;
+        self
+    }
+
+    pub fn params(mut self, params: ConsensusParams) -> Self {
+        self.params = params;
+        self
+    }
+
+    /// Derive all random choices from `seed` instead of `default_seed`.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    pub fn latency(mut self, latency: Latency) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    pub fn topology(mut self, topology: Topology) -> Self {
+        self.topology = topology;
+        self
+    }
+
+    /// Pick the nodes queries are sent to with `strategy`, see
+    /// `Network::set_sampling`.
+    pub fn sampling(mut self, strategy: Arc<dyn SamplingStrategy>) -> Self {
+        self.sampling = Some(strategy);
+        self
+    }
+
+    /// Let a random `fraction` of the nodes behave byzantine.
+    pub fn byzantine_fraction(mut self, fraction: f32, behavior: Behavior) -> Self {
+        self.byzantine = Some((fraction, behavior));
+        self
+    }
+
+    /// Validate transactions on every node with `validator`.
+    pub fn validator(mut self, validator: Arc<dyn Validator>) -> Self {
+        self.validator = Some(validator);
+        self
+    }
+
+    /// Measure all time of the network on `clock`, see `Network::set_clock`.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    /// Record every delivered message, see `Network::record`.
+    pub fn record(mut self) -> Self {
+        self.record = true;
+        self
+    }
+
+    pub fn build(self) -> Network {
+        let seed = self.seed.unwrap_or_else(default_seed);
+        let mut net = Network::with_params_seeded(self.nodes, self.params, seed);
+        net.set_latency(self.latency);
+        net.set_topology(self.topology);
+        if let Some(strategy) = self.sampling {
+            net.set_sampling(strategy);
+        }
+        if let Some((fraction, behavior)) = self.byzantine {
+            net.set_byzantine(fraction, behavior);
+        }
+        if let Some(validator) = self.validator {
+            for node in net.nodes().values() {
+                lock_node(node).validator = validator.clone();
+            }
+        }
+        if let Some(clock) = self.clock {
+            net.set_clock(clock);
+        }
+        if self.record {
+            net.record();
+        }
+        net
+    }
+}
+
+/// The nodes of a network by id.
+type Nodes = BTreeMap<u64, Arc<Mutex<Node>>>;
+
+#[derive(Debug)]
+pub struct Network {
+    /// Shared with the dispatcher thread, so nodes can join and leave while
+    /// the network is running.
+    nodes: Arc<RwLock<Nodes>>,
+    /// Decisions nodes of this network finalized.
+    decisions: Decisions,
+    /// Messages delivered per transaction.
+    stats: Arc<Mutex<MessageStats>>,
+    observers: Observers,
+    receiver: Arc<Mutex<Receiver<Envelope>>>,
+    /// Handed to nodes joining the network.
+    sender: Outbox,
+    params: ConsensusParams,
+    seed: u64,
+    rng: Mutex<SimRng>,
+    latency: Latency,
+    topology: Topology,
+    sampling: Arc<dyn SamplingStrategy>,
+    partition: Arc<Mutex<Partition>>,
+    clock: Arc<dyn Clock>,
+    /// Cleared to signal the dispatcher thread to exit.
+    running: Arc<AtomicBool>,
+    dispatcher: Option<JoinHandle<()>>,
+    /// Panic message of the dispatcher thread, if it died.
+    failure: Arc<Mutex<Option<String>>>,
+    /// Log of all delivered messages, if recording is enabled.
+    log: Option<Arc<Mutex<MessageLog>>>,
+}
+
+impl Network {
+    /// Create a new network with `n` participating nodes using the default
+    /// consensus parameters.
+    pub fn new(n: u64) -> Self {
+        Network::with_params(n, ConsensusParams::default())
+    }
+
+    /// Create a new network with `n` participating nodes using the given
+    /// consensus parameters.
+    pub fn with_params(n: u64, params: ConsensusParams) -> Self {
+        Network::with_params_seeded(n, params, default_seed())
+    }
+
+    /// Create a new network with `n` participating nodes whose random choices
+    /// are derived from `seed`.
+    pub fn new_seeded(n: u64, seed: u64) -> Self {
+        Network::with_params_seeded(n, ConsensusParams::default(), seed)
+    }
+
+    pub fn with_params_seeded(n: u64, params: ConsensusParams, seed: u64) -> Self {
+        let (sender, receiver) = match params.channel_capacity {
+            Some(capacity) => {
+                let (sender, receiver) = sync_channel(capacity);
+                (Outbox::bounded(sender), receiver)
+            }
+            None => {
+                let (sender, receiver) = channel();
+                (Outbox::from(sender), receiver)
+            }
+        };
+        let mut rng = seeded_rng(seed);
+        let decisions = Decisions::default();
+        decisions.lock().unwrap().members = n as usize;
+        let observers = Observers::default();
+        let nodes = (0..n)
+            .map(|id| {
+                let node_rng = SimRng::from_rng(&mut rng).unwrap();
+                let (decisions, observers) = (decisions.clone(), observers.clone());
+                let node = Node::new(id, sender.clone(), params, node_rng, decisions, observers);
+                (id, Arc::new(Mutex::new(node)))
+            })
+            .collect();
+        Network {
+            nodes: Arc::new(RwLock::new(nodes)),
+            decisions,
+            stats: Arc::default(),
+            observers,
+            receiver: Arc::new(Mutex::new(receiver)),
+            sender,
+            params,
+            seed,
+            rng: Mutex::new(rng),
+            latency: Latency::default(),
+            topology: Topology::default(),
+            sampling: Arc::new(StakeWeighted),
+            partition: Arc::default(),
+            clock: system_clock(),
+            running: Arc::new(AtomicBool::new(false)),
+            dispatcher: None,
+            failure: Arc::default(),
+            log: None,
+        }
+    }
+
+    /// Create a new network with `n` nodes of which a random `fraction`
+    /// behaves byzantine according to `behavior`.
+    pub fn with_byzantine(n: u64, fraction: f32, behavior: Behavior) -> Self {
+        let net = Network::new(n);
+        net.set_byzantine(fraction, behavior);
+        net
+    }
+
+    /// Configure a network with `NetworkBuilder`.
+    pub fn builder() -> NetworkBuilder {
+        NetworkBuilder::default()
+    }
+
+    /// Let a random `fraction` of the nodes behave byzantine.
+    fn set_byzantine(&self, fraction: f32, behavior: Behavior) {
+        let ids = self.nodes().keys().cloned().collect::<Vec<_>>();
+        let count = (fraction * ids.len() as f32).round() as usize;
+        let byzantine = seq::sample_iter(&mut *self.rng.lock().unwrap(), ids, count)
+            .unwrap_or_else(|ids| ids);
+        let nodes = self.nodes();
+        for id in byzantine {
+            lock_node(&nodes[&id]).behavior = behavior;
+        }
+    }
+
+    /// Create a new network with `n` nodes, each validating transactions with
+    /// the validator `factory` returns for its id. Returning the same
+    /// validator for every id gives all nodes consistent rules.
+    pub fn with_validator<F>(n: u64, mut factory: F) -> Self
+    where
+        F: FnMut(u64) -> Arc<dyn Validator>,
+    {
+        let net = Network::new(n);
+        for (id, node) in net.nodes().iter() {
+            lock_node(node).validator = factory(*id);
+        }
+        net
+    }
+
+    /// Change the rules node `id` validates transactions with.
+    pub fn set_validator(&self, id: u64, validator: Arc<dyn Validator>) -> Result {
+        self.configure(id, |node| node.validator = validator)
+    }
+
+    /// Change the behavior of node `id`.
+    pub fn set_behavior(&self, id: u64, behavior: Behavior) -> Result {
+        self.configure(id, |node| node.behavior = behavior)
+    }
+
+    /// Change the stake of node `id`. Nodes are sampled proportional to their
+    /// stake and their responses weigh according to their stake.
+    pub fn set_stake(&self, id: u64, stake: u64) -> Result {
+        self.configure(id, |node| node.stake = stake)
+    }
+
+    /// Delay every query response of node `id` by `delay` on top of the
+    /// latency of the network, to simulate a slow node. A delay above the
+    /// query timeout makes its peers re-sample around it.
+    pub fn set_response_delay(&self, id: u64, delay: Duration) -> Result {
+        self.configure(id, |node| node.response_delay = delay)
+    }
+
+    /// Change node `id` with `f`, or fail with `UnknownNode`.
+    fn configure<F: FnOnce(&mut Node)>(&self, id: u64, f: F) -> Result {
+        let nodes = self.nodes();
+        let node = nodes.get(&id).ok_or(AvalancheError::UnknownNode(id))?;
+        f(&mut lock_node(node));
+        Ok(())
+    }
+
+    /// Returns the ids of all nodes not behaving honestly.
+    pub fn byzantine_nodes(&self) -> Vec<u64> {
+        self.nodes()
+            .iter()
+            .filter(|(_, node)| lock_node(node).behavior != Behavior::Honest)
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    /// Returns the ids of all nodes that panicked while handling a message,
+    /// see `Node::is_failed`.
+    pub fn failed_nodes(&self) -> Vec<u64> {
+        self.nodes()
+            .iter()
+            .filter(|(_, node)| node.lock().map_or(true, |node| node.failed))
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    /// Returns a snapshot of all transactions finalized by any node of the
+    /// network, with the status they were first finalized to.
+    pub fn finalized(&self) -> HashMap<Hash, Status> {
+        self.decisions
+            .lock()
+            .unwrap()
+            .finalized
+            .iter()
+            .map(|(hash, (_, status))| (hash.clone(), status.clone()))
+            .collect()
+    }
+
+    /// Returns true if any node of the network finalized the transaction.
+    pub fn is_finalized(&self, hash: &Hash) -> bool {
+        self.decisions.lock().unwrap().finalized.contains_key(hash)
+    }
+
+    /// Returns the number of nodes that finalized the transaction.
+    pub fn finalized_by(&self, hash: &Hash) -> usize {
+        self.nodes()
+            .values()
+            .filter(|node| {
+                let node = lock_node(node);
+                node.mempool.get(hash).is_some_and(|state| state.is_final)
+            })
+            .count()
+    }
+
+    /// Returns the status the network decided on for the transaction, once at
+    /// least a `DECISION_QUORUM` fraction of the nodes finalized it to that
+    /// status. Unlike `is_finalized`, a single node is not enough.
+    pub fn network_decision(&self, hash: &Hash) -> Option<Status> {
+        let nodes = self.nodes();
+        let needed = decision_quorum(nodes.len());
+        let (mut valid, mut invalid) = (0, 0);
+        for node in nodes.values() {
+            let node = lock_node(node);
+            match node.mempool.get(hash) {
+                Some(state) if state.is_final && state.status == Status::Valid => valid += 1,
+                Some(state) if state.is_final && state.status == Status::Invalid => invalid += 1,
+                _ => {}
+            }
+        }
+        match (valid >= needed, invalid >= needed) {
+            (true, _) => Some(Status::Valid),
+            (_, true) => Some(Status::Invalid),
+            _ => None,
+        }
+    }
+
+    /// Returns every safety violation detected so far, in the order they
+    /// were detected.
+    pub fn safety_violations(&self) -> Vec<SafetyViolation> {
+        self.decisions.lock().unwrap().violations.clone()
+    }
+
+    /// Returns an error with the first safety violation, if any node
+    /// disagreed with the finalized decision of another node.
+    pub fn check_safety(&self) -> Result {
+        match self.decisions.lock().unwrap().violations.first() {
+            Some(v) => Err(AvalancheError::SafetyViolation(v.clone())),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the ids of all nodes in the network, in ascending order.
+    pub fn node_ids(&self) -> Vec<u64> {
+        self.nodes().keys().cloned().collect()
+    }
+
+    /// Returns node `id` for inspection.
+    pub fn node(&self, id: u64) -> Option<Arc<Mutex<Node>>> {
+        self.nodes().get(&id).cloned()
+    }
+
+    /// Ask node `id` how confident it is about the given transaction, see
+    /// `Node::query_confidence`.
+    pub fn query_confidence(&self, id: u64, hash: &Hash) -> Result<Option<Confidence>> {
+        let node = self.node(id).ok_or(AvalancheError::UnknownNode(id))?;
+        let node = node.lock().map_err(|_| AvalancheError::Poisoned(id))?;
+        Ok(node.query_confidence(hash))
+    }
+
+    fn nodes(&self) -> RwLockReadGuard<'_, Nodes> {
+        self.nodes.read().unwrap()
+    }
+
+    /// Add a new honest node to the network and return its id. The node is
+    /// sampled by queries dispatched from now on, also while the network is
+    /// running. With a topology other than the full mesh the node only gets
+    /// sampled by the peers it got connected to. The node asks a random peer
+    /// for the transactions it is still deciding on and pulls those it
+    /// misses, so it takes part in deciding them as well.
+    pub fn add_node(&self) -> u64 {
+        let mut nodes = self.nodes.write().unwrap();
+        let id = nodes.keys().next_back().map_or(0, |id| id + 1);
+        let rng = SimRng::from_rng(&mut *self.rng.lock().unwrap()).unwrap();
+        let (decisions, observers) = (self.decisions.clone(), self.observers.clone());
+        let mut node = Node::new(id, self.sender.clone(), self.params, rng, decisions, observers);
+        node.clock = self.clock.clone();
+        // Catch up on the transactions the network is deciding on.
+        if let Err(err) = node.sender.send(id, Message::MempoolRequest) {
+            warn!("node={} failed to request a mempool digest: {}", id, err);
+        }
+        nodes.insert(id, Arc::new(Mutex::new(node)));
+        self.decisions.lock().unwrap().set_members(nodes.len());
+        id
+    }
+
+    /// Remove node `id` from the network. The node is not sampled anymore and
+    /// messages still addressed to it or sent by it are dropped. Responses it
+    /// gave in rounds that are still open no longer count towards their
+    /// quorum, those rounds get re-sampled once they time out.
+    pub fn remove_node(&self, id: u64) -> Result {
+        let mut nodes = self.nodes.write().unwrap();
+        match nodes.remove(&id) {
+            Some(_) => {
+                for node in nodes.values() {
+                    lock_node(node).forget_peer(id);
+                }
+                self.decisions.lock().unwrap().set_members(nodes.len());
+                Ok(())
+            }
+            None => Err(AvalancheError::UnknownNode(id)),
+        }
+    }
+
+    /// The seed this network derives its random choices from.
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Hand the transaction to a random node in the network, which verifies
+    /// it and starts querying its peers. The returned `Decision` receives the
+    /// status the network decides on, dropping it does not affect consensus.
+    pub fn inject(&self, tx: Transaction) -> Result<Decision> {
+        self.health()?;
+        let nodes = self.nodes();
+        let ids = nodes.keys().cloned().collect::<Vec<_>>();
+        // Without any node left there is nobody to hand the transaction to.
+        let id = *self
+            .rng
+            .lock()
+            .unwrap()
+            .choose(&ids)
+            .ok_or(AvalancheError::Disconnected)?;
+        let hash = tx.id().clone();
+        let receiver = self.decisions.lock().unwrap().wait_for(&hash);
+        let envelope = self.sender.envelope(id, Message::Transaction(tx));
+        deliver(&nodes, &self.log, &self.stats, id, &envelope)?;
+        Ok(Decision { hash, receiver })
+    }
+
+    /// Hand the transaction to node `id`, e.g. to have different nodes start
+    /// querying different transactions at the same time.
+    pub fn inject_to(&self, id: u64, tx: Transaction) -> Result {
+        self.health()?;
+        let envelope = self.sender.envelope(id, Message::Transaction(tx));
+        deliver(&self.nodes(), &self.log, &self.stats, id, &envelope)
+    }
+
+    /// Hand the transactions to a random node in the network, which queries
+    /// its peers for all of them in a single batch.
+    pub fn inject_batch(&self, txs: Vec<Transaction>) -> Result {
+        self.health()?;
+        let nodes = self.nodes();
+        let ids = nodes.keys().cloned().collect::<Vec<_>>();
+        let id = *self
+            .rng
+            .lock()
+            .unwrap()
+            .choose(&ids)
+            .ok_or(AvalancheError::Disconnected)?;
+        let envelope = self.sender.envelope(id, Message::Transactions(txs));
+        deliver(&nodes, &self.log, &self.stats, id, &envelope)
+    }
+
+    /// Hand the transaction to every node in the network, each of which
+    /// verifies it with its own validator before querying its peers. Unlike
+    /// `inject`, nodes with different validators start out disagreeing.
+    pub fn broadcast(&self, tx: Transaction) -> Result {
+        self.health()?;
+        let nodes = self.nodes();
+        let msg = Message::Transaction(tx);
+        for &id in nodes.keys() {
+            let envelope = self.sender.envelope(id, msg.clone());
+            deliver(&nodes, &self.log, &self.stats, id, &envelope)?;
+        }
+        Ok(())
+    }
+
+    /// Record every message delivered from now on. Must be enabled before
+    /// calling `run` to capture the messages of the dispatcher.
+    pub fn record(&mut self) {
+        if self.log.is_none() {
+            self.log = Some(Arc::new(Mutex::new(MessageLog::default())));
+        }
+    }
+
+    /// Returns a copy of the messages recorded so far, `None` if recording is
+    /// not enabled.
+    pub fn message_log(&self) -> Option<MessageLog> {
+        self.log.as_ref().map(|log| log.lock().unwrap().clone())
+    }
+
+    /// Deliver the messages of the log to the nodes in the exact recorded
+    /// order. Messages the nodes send while replaying are discarded, since
+    /// their deliveries are part of the log already. The network must not be
+    /// running while replaying.
+    pub fn replay(&self, log: &MessageLog) -> Result {
+        for entry in &log.entries {
+            let envelope = Envelope {
+                seq: entry.seq,
+                origin: entry.origin,
+                msg: entry.msg.clone(),
+            };
+            deliver(&self.nodes(), &self.log, &self.stats, entry.to, &envelope)?;
+            while self.receiver.lock().unwrap().try_recv().is_ok() {}
+        }
+        Ok(())
+    }
+
+    /// Collect the finalization latencies of every node in the network.
+    pub fn latency_histogram(&self) -> LatencyHistogram {
+        LatencyHistogram::new(
+            self.nodes()
+                .values()
+                .flat_map(|node| lock_node(node).latencies().into_values())
+                .collect(),
+        )
+    }
+
+    /// Summarize the outcome of every transaction known to the network.
+    pub fn report(&self) -> SimulationReport {
+        SimulationReport::collect(self)
+    }
+
+    /// Capture the consensus state of every node and the decisions of the
+    /// network, to resume it later with `restore`.
+    pub fn snapshot(&self) -> NetworkSnapshot {
+        NetworkSnapshot::capture(self)
+    }
+
+    /// Rebuild a stopped network from a snapshot, see `NetworkSnapshot` for
+    /// what has to be configured again.
+    pub fn restore(snapshot: &NetworkSnapshot) -> Network {
+        snapshot.restore()
+    }
+
+    /// Returns a Graphviz DOT graph of the color every node holds for `hash`,
+    /// e.g. to render snapshots of the colors spreading through the network.
+    pub fn to_dot(&self, hash: &Hash) -> String {
+        dot::to_dot(self, hash)
+    }
+
+    /// Call `observer` for every event of every node from now on. Observers
+    /// run on the thread handling the message while the node is locked, so
+    /// they must not access the nodes of the network themselves.
+    pub fn on_event<F>(&self, observer: F)
+    where
+        F: Fn(&Event) + Send + Sync + 'static,
+    {
+        self.observers.0.write().unwrap().push(Box::new(observer));
+    }
+
+    /// Returns the number of messages dropped because the network was
+    /// saturated, see `ConsensusParams::channel_capacity`.
+    pub fn dropped_messages(&self) -> usize {
+        self.sender.dropped()
+    }
+
+    /// Returns the number of query and response messages delivered so far,
+    /// per transaction.
+    pub fn message_stats(&self) -> MessageStats {
+        self.stats.lock().unwrap().clone()
+    }
+
+    /// Summarize the network as it is now, e.g. at the end of a benchmark.
+    /// Only the counters kept by the network and the nodes are read, messages
+    /// in flight are left alone.
+    pub fn stats(&self) -> NetworkStats {
+        let (mut finalizations, mut epochs) = (0u64, 0u64);
+        let mut mempool_sizes = BTreeMap::new();
+        for (id, node) in self.nodes().iter() {
+            let node = lock_node(node);
+            mempool_sizes.insert(*id, node.mempool.len());
+            for state in node.mempool.values() {
+                if state.is_final && !state.tx.is_genesis() {
+                    finalizations += 1;
+                    epochs += u64::from(state.epoch);
+                }
+            }
+        }
+        let ledger = self.decisions.lock().unwrap();
+        let genesis = Transaction::genesis();
+        NetworkStats {
+            messages: self.stats.lock().unwrap().total(),
+            dropped_messages: self.dropped_messages(),
+            finalized: ledger
+                .finalized
+                .keys()
+                .filter(|hash| *hash != genesis.id())
+                .count(),
+            avg_epochs: match finalizations {
+                0 => None,
+                n => Some(epochs as f64 / n as f64),
+            },
+            mempool_sizes,
+            safety_violations: ledger.violations.clone(),
+        }
+    }
+
+    /// Dispatch exactly one queued message on the calling thread. Returns
+    /// false if there was no message to dispatch. Together with a seeded
+    /// network this executes the protocol fully deterministically, without
+    /// latency or timeouts. The network must not be running.
+    pub fn step(&self) -> Result<bool> {
+        let next = self.receiver.lock().unwrap().try_recv();
+        match next {
+            Ok(envelope) => {
+                let mut rng = self.rng.lock().unwrap();
+                let nodes = &self.nodes();
+                let sampler = Sampler {
+                    topology: &self.topology,
+                    strategy: &*self.sampling,
+                };
+                let (log, stats, partition) = (&self.log, &self.stats, &self.partition);
+                dispatch(&mut *rng, nodes, sampler, log, stats, partition, &envelope)?;
+                Ok(true)
+            }
+            Err(TryRecvError::Empty) => Ok(false),
+            Err(TryRecvError::Disconnected) => Err(AvalancheError::Disconnected),
+        }
+    }
+
+    /// Dispatch messages on the calling thread until no message is left.
+    /// Returns the number of dispatched messages.
+    pub fn run_until_quiescent(&self) -> Result<usize> {
+        let mut steps = 0;
+        while self.step()? {
+            steps += 1;
+        }
+        Ok(steps)
+    }
+
+    /// Like `run_until_quiescent`, but lets the nodes handle their messages
+    /// on up to `threads` threads. Messages are dispatched in waves: every
+    /// queued message is addressed on the calling thread, ordered by the
+    /// node that sent it, then every node handles the messages of the wave
+    /// addressed to it in that order. Each thread handles a fixed share of
+    /// the nodes, nothing is rebalanced while a wave runs. The finalized
+    /// statuses and the recorded message log are fully determined by the
+    /// seed and parameters of the network, the same for any number of
+    /// threads, but differ from `run_until_quiescent`. Which node the ledger
+    /// saw finalize a transaction first, e.g. in `SafetyViolation::first`,
+    /// depends on how the threads are scheduled. With a single thread
+    /// everything runs on the calling thread. Returns the number of
+    /// dispatched messages.
+    pub fn run_until_quiescent_parallel(&self, threads: usize) -> Result<usize> {
+        let mut dispatched = 0;
+        loop {
+            let mut wave = Vec::new();
+            {
+                let receiver = self.receiver.lock().unwrap();
+                loop {
+                    match receiver.try_recv() {
+                        Ok(envelope) => wave.push(envelope),
+                        Err(TryRecvError::Empty) => break,
+                        Err(TryRecvError::Disconnected) => {
+                            return Err(AvalancheError::Disconnected)
+                        }
+                    }
+                }
+            }
+            if wave.is_empty() {
+                return Ok(dispatched);
+            }
+            dispatched += wave.len();
+            // Nodes on different threads send in any order, but every node
+            // sends its own messages in order. Renumber the messages in that
+            // order, the numbers the threads drew depend on the scheduling.
+            wave.sort_by_key(|envelope| envelope.origin);
+            for envelope in &mut wave {
+                envelope.seq = self.sender.next_seq();
+            }
+
+            let nodes = &self.nodes();
+            let mut deliveries: BTreeMap<u64, Vec<Envelope>> = BTreeMap::new();
+            {
+                let mut rng = self.rng.lock().unwrap();
+                let mut partition = self.partition.lock().unwrap();
+                let sampler = Sampler {
+                    topology: &self.topology,
+                    strategy: &*self.sampling,
+                };
+                for envelope in &wave {
+                    let (recipients, envelope) = address(&mut *rng, nodes, sampler, envelope);
+                    for to in recipients {
+                        if partition.separates(envelope.origin, to) {
+                            partition.held.push((to, envelope.clone()));
+                            continue;
+                        }
+                        // Recorded here, in the order of the wave.
+                        if let Some(ref log) = self.log {
+                            log.lock().unwrap().push(to, envelope.clone());
+                        }
+                        deliveries.entry(to).or_default().push(envelope.clone());
+                    }
+                }
+            }
+
+            let mut shares = (0..threads.max(1)).map(|_| Vec::new()).collect::<Vec<_>>();
+            let n = shares.len();
+            for (i, delivery) in deliveries.into_iter().enumerate() {
+                shares[i % n].push(delivery);
+            }
+            let stats = &self.stats;
+            let handle = |share: Vec<(u64, Vec<Envelope>)>| -> Result {
+                for (to, envelopes) in share {
+                    for envelope in &envelopes {
+                        deliver(nodes, &None, stats, to, envelope)?;
+                    }
+                }
+                Ok(())
+            };
+            let results = match n {
+                1 => vec![handle(shares.pop().unwrap())],
+                _ => thread::scope(|scope| {
+                    let workers = shares
+                        .into_iter()
+                        .map(|share| scope.spawn(move || handle(share)))
+                        .collect::<Vec<_>>();
+                    workers
+                        .into_iter()
+                        .map(|worker| worker.join().unwrap())
+                        .collect::<Vec<_>>()
+                }),
+            };
+            for result in results {
+                result?;
+            }
+        }
+    }
+
+    /// Delay every dispatched message according to the given latency model.
+    /// Must be set before calling `run`.
+    pub fn set_latency(&mut self, latency: Latency) {
+        self.latency = latency;
+    }
+
+    /// Measure timeouts, ttls and message latencies on `clock` instead of the
+    /// system clock. With a `MockClock` queries only time out and delayed
+    /// messages only arrive once the clock is advanced. Must be set before
+    /// calling `run`.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        for node in self.nodes().values() {
+            lock_node(node).clock = clock.clone();
+        }
+        self.clock = clock;
+    }
+
+    /// Restrict the nodes to sample their queries from the peers they know in
+    /// the given topology. Must be set before calling `run`.
+    pub fn set_topology(&mut self, topology: Topology) {
+        self.topology = topology;
+    }
+
+    /// Pick the nodes queries are sent to with `strategy` instead of
+    /// `StakeWeighted`. Must be set before calling `run`.
+    pub fn set_sampling(&mut self, strategy: Arc<dyn SamplingStrategy>) {
+        self.sampling = strategy;
+    }
+
+    /// Cut the network in two: messages between a node of `a` and a node of
+    /// `b` are held back until `heal` is called, while nodes in neither group
+    /// still reach everybody. Nodes keep sampling peers on the other side, so
+    /// rounds with such a peer stall until they are re-sampled or the
+    /// partition heals.
+    pub fn partition(&self, a: &[u64], b: &[u64]) {
+        let mut partition = self.partition.lock().unwrap();
+        partition.groups.clear();
+        partition.groups.extend(a.iter().map(|id| (*id, 0)));
+        partition.groups.extend(b.iter().map(|id| (*id, 1)));
+    }
+
+    /// Remove the partition and deliver all messages it held back.
+    pub fn heal(&self) -> Result {
+        let held = {
+            let mut partition = self.partition.lock().unwrap();
+            partition.groups.clear();
+            mem::take(&mut partition.held)
+        };
+        for (to, envelope) in held {
+            // The sender left the network meanwhile.
+            if !self.nodes().contains_key(&envelope.origin) {
+                continue;
+            }
+            match deliver(&self.nodes(), &self.log, &self.stats, to, &envelope) {
+                // The recipient left the network meanwhile.
+                Err(AvalancheError::UnknownNode(_)) => {}
+                result => result?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Start dispatching messages between the nodes on a background thread.
+    /// Calling `run` on a running network has no effect.
+    pub fn run(&mut self) {
+        if self.dispatcher.is_some() {
+            return;
+        }
+        let receiver = self.receiver.clone();
+        let nodes = self.nodes.clone();
+        let log = self.log.clone();
+        let stats = self.stats.clone();
+        let latency = self.latency;
+        let topology = self.topology.clone();
+        let sampling = self.sampling.clone();
+        let partition = self.partition.clone();
+        let clock = self.clock.clone();
+        let capacity = self.params.channel_capacity;
+        let mut rng = SimRng::from_rng(&mut *self.rng.lock().unwrap()).unwrap();
+        let running = self.running.clone();
+        running.store(true, AtomicOrdering::SeqCst);
+        let (failure, stopped) = (self.failure.clone(), self.running.clone());
+
+        let dispatcher = move || {
+            let mut last_sweep = clock.now();
+            let mut queue = BinaryHeap::new();
+            let mut due = FairQueue::default();
+            while running.load(AtomicOrdering::SeqCst) {
+                // Deliver every message whose delay has passed, taking turns
+                // between the nodes that sent them.
+                while queue
+                    .peek()
+                    .is_some_and(|msg: &Reverse<Delayed>| msg.0.at <= clock.now())
+                {
+                    due.push(queue.pop().unwrap().0.envelope);
+                }
+                while let Some(envelope) = due.pop() {
+                    let nodes = &nodes.read().unwrap();
+                    let envelope = &envelope;
+                    let sampler = Sampler {
+                        topology: &topology,
+                        strategy: &*sampling,
+                    };
+                    let result =
+                        dispatch(&mut rng, nodes, sampler, &log, &stats, &partition, envelope);
+                    if !handled(result) {
+                        return;
+                    }
+                }
+
+                let timeout = queue.peek().map_or(QUERY_TIMEOUT, |msg| {
+                    msg.0
+                        .at
+                        .saturating_duration_since(clock.now())
+                        .min(QUERY_TIMEOUT)
+                });
+                // Leave messages in the channel while too many are in flight,
+                // so the nodes notice the network is saturated.
+                if capacity.is_some_and(|capacity| queue.len() >= capacity) {
+                    clock.sleep(timeout);
+                    continue;
+                }
+                let next = receiver.lock().unwrap().recv_timeout(timeout);
+                match next {
+                    Ok(envelope) => {
+                        // Take every other waiting message as well, so a node
+                        // flooding the channel only delays its own messages.
+                        let receiver = receiver.lock().unwrap();
+                        let mut next = Some(envelope);
+                        while let Some(envelope) = next {
+                            let delay = latency.sample(&mut rng)
+                                + response_delay(&nodes.read().unwrap(), &envelope);
+                            let at = clock.now() + delay;
+                            queue.push(Reverse(Delayed { at, envelope }));
+                            next = match capacity {
+                                Some(capacity) if queue.len() >= capacity => None,
+                                _ => receiver.try_recv().ok(),
+                            };
+                        }
+                    }
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+
+                // Periodically let every node re-sample its stalled queries.
+                if clock.now().saturating_duration_since(last_sweep) >= QUERY_TIMEOUT {
+                    for (id, node) in nodes.read().unwrap().iter() {
+                        let result = node
+                            .lock()
+                            .map_err(|_| AvalancheError::Poisoned(*id))
+                            .and_then(|mut node| guarded(&mut node, Node::check_timeouts));
+                        if !handled(result) {
+                            return;
+                        }
+                    }
+                    last_sweep = clock.now();
+                }
+            }
+        };
+        self.dispatcher = Some(thread::spawn(move || {
+            // Remember why the dispatcher died, instead of leaving the network
+            // hanging silently.
+            if let Err(panic) = panic::catch_unwind(AssertUnwindSafe(dispatcher)) {
+                let msg = panic_message(&*panic);
+                error!("dispatcher panicked: {}", msg);
+                *failure.lock().unwrap() = Some(msg);
+                stopped.store(false, AtomicOrdering::SeqCst);
+            }
+        }));
+    }
+
+    /// Start the network if it is not running yet, inject the transactions and
+    /// block until every one of them is finalized by at least a `quorum`
+    /// fraction of the nodes. Returns the report of the network once they
+    /// are, or an error if that takes longer than `timeout`. The timeout is
+    /// measured on the system clock, also if another clock is set.
+    pub fn run_blocking(
+        &mut self,
+        txs: Vec<Transaction>,
+        quorum: f64,
+        timeout: Duration,
+    ) -> Result<SimulationReport> {
+        let started = Instant::now();
+        self.run();
+        let hashes = txs.iter().map(|tx| tx.id().clone()).collect::<Vec<_>>();
+        for tx in txs {
+            self.inject(tx)?;
+        }
+        loop {
+            self.health()?;
+            let nodes = self.nodes().len();
+            let needed = ((quorum.clamp(0.0, 1.0) * nodes as f64).ceil() as usize).max(1);
+            if hashes.iter().all(|hash| self.finalized_by(hash) >= needed) {
+                return Ok(self.report());
+            }
+            if started.elapsed() >= timeout {
+                return Err(AvalancheError::Timeout(timeout));
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// Returns an error if the dispatcher thread panicked, after which the
+    /// network does not deliver any more messages.
+    pub fn health(&self) -> Result {
+        match *self.failure.lock().unwrap() {
+            Some(ref msg) => Err(AvalancheError::DispatcherPanicked(msg.clone())),
+            None => Ok(()),
+        }
+    }
+
+    /// Stop the dispatcher thread and wait for it to exit. Messages that are
+    /// still in flight are dropped.
+    pub fn shutdown(&mut self) {
+        self.running.store(false, AtomicOrdering::SeqCst);
+        if let Some(dispatcher) = self.dispatcher.take() {
+            let _ = dispatcher.join();
+        }
+    }
+}
+
+impl Drop for Network {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// The message a thread panicked with, if it is a string.
+fn panic_message(panic: &(dyn Any + Send)) -> String {
+    match (panic.downcast_ref::<&str>(), panic.downcast_ref::<String>()) {
+        (Some(msg), _) => msg.to_string(),
+        (_, Some(msg)) => msg.clone(),
+        _ => "unknown panic".to_string(),
+    }
+}
+
+/// Run `f` on `node` unless the node failed before. A panic marks the node
+/// failed instead of taking down the dispatcher and every other node with
+/// it: a failed node is no longer sampled and drops every message it gets.
+fn guarded<F: FnOnce(&mut Node) -> Result>(node: &mut Node, f: F) -> Result {
+    if node.failed {
+        return Ok(());
+    }
+    match panic::catch_unwind(AssertUnwindSafe(|| f(node))) {
+        Ok(result) => result,
+        Err(panic) => {
+            error!("node={} panicked and failed: {}", node.id, panic_message(&*panic));
+            node.failed = true;
+            Err(AvalancheError::Poisoned(node.id))
+        }
+    }
+}
+
+/// Lock a node even if a thread panicked while holding its mutex, to read
+/// or configure it. Panics while handling messages never poison the mutex,
+/// see `guarded`.
+fn lock_node(node: &Mutex<Node>) -> MutexGuard<'_, Node> {
+    node.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Report a failure to handle a message. Returns false if the dispatcher has
+/// to stop because the network is gone.
+fn handled(result: Result) -> bool {
+    match result {
+        Ok(()) => true,
+        Err(AvalancheError::Disconnected) => false,
+        Err(err) => {
+            warn!("dispatcher: {}", err);
+            true
+        }
+    }
+}
+
+/// Deliver a message to its recipients. Queries are delivered to a random
+/// sample of nodes, responses to the node that sent the query.
+fn dispatch<R: Rng>(
+    rng: &mut R,
+    nodes: &Nodes,
+    sampler: Sampler,
+    log: &Option<Arc<Mutex<MessageLog>>>,
+    stats: &Mutex<MessageStats>,
+    partition: &Mutex<Partition>,
+    envelope: &Envelope,
+) -> Result {
+    let (recipients, envelope) = address(rng, nodes, sampler, envelope);
+    for to in recipients {
+        // Messages across a partition wait until it heals.
+        let mut partition = partition.lock().unwrap();
+        if partition.separates(envelope.origin, to) {
+            partition.held.push((to, envelope.clone()));
+            continue;
+        }
+        drop(partition);
+        deliver(nodes, log, stats, to, &envelope)?;
+    }
+    Ok(())
+}
+
+/// Returns the recipients of a message, sampling them for queries, along
+/// with the message to deliver to them.
+fn address<R: Rng>(
+    rng: &mut R,
+    nodes: &Nodes,
+    sampler: Sampler,
+    envelope: &Envelope,
+) -> (Vec<u64>, Envelope) {
+    let (seq, origin) = (envelope.seq, envelope.origin);
+    // Whatever a node sent before it left is stale.
+    if !nodes.contains_key(&origin) {
+        debug!("node={} left, dropping its message", origin);
+        return (Vec::new(), envelope.clone());
+    }
+    // Only peers the origin knows can be sampled.
+    let mut sample = |exclude: &[u64], samples| {
+        let sampled = sampler.sample(rng, nodes, origin, exclude, samples);
+        // Nobody gets the query, `check_timeouts` tries again later.
+        if sampled.is_empty() && samples > 0 {
+            warn!("node={} has no peers to sample, dropping its query", origin);
+        }
+        let stake = sampled.iter().map(|id| stake_of(nodes, *id)).sum::<u64>();
+        (sampled, stake)
+    };
+    match envelope.msg {
+        Message::Query(ref query) => {
+            let (sampled, stake) = sample(&query.exclude, query.samples);
+            // A re-sampled query completes the round of the nodes it
+            // excludes, so the round's sample includes their stake.
+            let responded = query
+                .exclude
+                .iter()
+                .filter(|id| nodes.contains_key(id))
+                .map(|id| stake_of(nodes, *id))
+                .sum::<u64>();
+            let mut query = query.clone();
+            query.sampled_stake = stake + responded;
+            let msg = Message::Query(query);
+            (sampled, Envelope { seq, origin, msg })
+        }
+        Message::QueryBatch(ref batch) => {
+            let (sampled, stake) = sample(&[], batch.samples);
+            let mut batch = batch.clone();
+            batch.sampled_stake = stake;
+            let msg = Message::QueryBatch(batch);
+            (sampled, Envelope { seq, origin, msg })
+        }
+        Message::MempoolRequest => (sample(&[], 1).0, envelope.clone()),
+        Message::QueryResponse((to, _))
+        | Message::QueryBatchResponse((to, _))
+        | Message::Decided((to, _, _))
+        | Message::MempoolDigest((to, _))
+        | Message::Pull((to, _))
+        | Message::Pulled((to, _)) => {
+            // The node that sent the query left the network.
+            if !nodes.contains_key(&to) {
+                debug!("node={} left, dropping response from {}", to, origin);
+                return (Vec::new(), envelope.clone());
+            }
+            (vec![to], envelope.clone())
+        }
+        _ => unreachable!(),
+    }
+}
+
+/// Groups of nodes that can't reach each other, see `Network::partition`.
+#[derive(Debug, Default)]
+struct Partition {
+    /// The group of every partitioned node.
+    groups: HashMap<u64, usize>,
+    /// Messages between the groups with their recipient, delivered once the
+    /// partition heals.
+    held: Vec<(u64, Envelope)>,
+}
+
+impl Partition {
+    /// Returns true if `a` and `b` are in different groups. Nodes outside
+    /// of all groups reach everybody.
+    fn separates(&self, a: u64, b: u64) -> bool {
+        match (self.groups.get(&a), self.groups.get(&b)) {
+            (Some(a), Some(b)) => a != b,
+            _ => false,
+        }
+    }
+}
+
+/// Let node `to` handle the message, recording the delivery if a log is set.
+fn deliver(
+    nodes: &Nodes,
+    log: &Option<Arc<Mutex<MessageLog>>>,
+    stats: &Mutex<MessageStats>,
+    to: u64,
+    envelope: &Envelope,
+) -> Result {
+    let (origin, msg) = (envelope.origin, &envelope.msg);
+    if let Some(log) = log {
+        log.lock().unwrap().push(to, envelope.clone());
+    }
+    stats.lock().unwrap().count(msg);
+    let mut node = nodes
+        .get(&to)
+        .ok_or(AvalancheError::UnknownNode(to))?
+        .lock()
+        .map_err(|_| AvalancheError::Poisoned(to))?;
+    guarded(&mut node, |node| node.handle_message(origin, msg))
+}
+
+/// A single recorded delivery of a message from `origin` to `to`. A query
+/// delivered to a whole sample is recorded once per peer, with the same `seq`.
+#[derive(Debug, Clone)]
+pub struct LogEntry {
+    pub to: u64,
+    pub seq: u64,
+    pub origin: u64,
+    pub msg: Message,
+}
+
+/// Ordered log of every message delivered in a network. The log can be
+/// written to and read from a compact binary format so a failing run can be
+/// attached to a bug report and replayed with `Network::replay`.
+#[derive(Debug, Clone, Default)]
+pub struct MessageLog {
+    pub entries: Vec<LogEntry>,
+}
+
+impl MessageLog {
+    fn push(&mut self, to: u64, envelope: Envelope) {
+        self.entries.push(LogEntry {
+            to,
+            seq: envelope.seq,
+            origin: envelope.origin,
+            msg: envelope.msg,
+        });
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Write the log in its binary format.
+    pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_u64::<LittleEndian>(self.entries.len() as u64)?;
+        for entry in &self.entries {
+            w.write_u64::<LittleEndian>(entry.to)?;
+            w.write_u64::<LittleEndian>(entry.seq)?;
+            w.write_u64::<LittleEndian>(entry.origin)?;
+            write_message(w, &entry.msg)?;
+        }
+        Ok(())
+    }
+
+    /// Read a log written by `write_to`.
+    pub fn read_from<R: Read>(r: &mut R) -> io::Result<MessageLog> {
+        let len = r.read_u64::<LittleEndian>()?;
+        let mut log = MessageLog::default();
+        for _ in 0..len {
+            let to = r.read_u64::<LittleEndian>()?;
+            let seq = r.read_u64::<LittleEndian>()?;
+            let origin = r.read_u64::<LittleEndian>()?;
+            let msg = read_message(r)?;
+            log.push(to, Envelope { seq, origin, msg });
+        }
+        Ok(log)
+    }
+}
+
+fn invalid_data(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, what)
+}
+
+fn write_bytes<W: Write>(w: &mut W, bytes: &[u8]) -> io::Result<()> {
+    w.write_u32::<LittleEndian>(bytes.len() as u32)?;
+    w.write_all(bytes)
+}
+
+/// Read bytes written by `write_bytes`. Only what is actually there is
+/// allocated, whatever length the input claims.
+fn read_bytes<R: Read>(r: &mut R) -> io::Result<Vec<u8>> {
+    let len = r.read_u32::<LittleEndian>()?;
+    let mut bytes = Vec::new();
+    r.take(u64::from(len)).read_to_end(&mut bytes)?;
+    if bytes.len() != len as usize {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated bytes"));
+    }
+    Ok(bytes)
+}
+
+fn read_hash<R: Read>(r: &mut R) -> io::Result<Hash> {
+    let mut hash = [0; 32];
+    r.read_exact(&mut hash)?;
+    Ok(Hash(hash))
+}
+
+fn write_status<W: Write>(w: &mut W, status: &Status) -> io::Result<()> {
+    w.write_u8(match status {
+        Status::Valid => 0,
+        Status::Invalid => 1,
+        Status::Unknown => 2,
+    })
+}
+
+fn read_status<R: Read>(r: &mut R) -> io::Result<Status> {
+    match r.read_u8()? {
+        0 => Ok(Status::Valid),
+        1 => Ok(Status::Invalid),
+        2 => Ok(Status::Unknown),
+        _ => Err(invalid_data("invalid status")),
+    }
+}
+
+fn write_reason<W: Write>(w: &mut W, reason: Option<InvalidReason>) -> io::Result<()> {
+    w.write_u8(match reason {
+        None => 0,
+        Some(InvalidReason::DataOverLimit) => 1,
+        Some(InvalidReason::BadSignature) => 2,
+        Some(InvalidReason::Conflict) => 3,
+        Some(InvalidReason::InvalidParent) => 4,
+        Some(InvalidReason::Validator) => 5,
+    })
+}
+
+fn read_reason<R: Read>(r: &mut R) -> io::Result<Option<InvalidReason>> {
+    match r.read_u8()? {
+        0 => Ok(None),
+        1 => Ok(Some(InvalidReason::DataOverLimit)),
+        2 => Ok(Some(InvalidReason::BadSignature)),
+        3 => Ok(Some(InvalidReason::Conflict)),
+        4 => Ok(Some(InvalidReason::InvalidParent)),
+        5 => Ok(Some(InvalidReason::Validator)),
+        _ => Err(invalid_data("invalid reason")),
+    }
+}
+
+fn write_message<W: Write>(w: &mut W, msg: &Message) -> io::Result<()> {
+    match msg {
+        Message::Query(query) => {
+            w.write_u8(0)?;
+            query.tx.write_to(w)?;
+            write_status(w, &query.status)?;
+            w.write_u64::<LittleEndian>(query.samples as u64)?;
+            w.write_u32::<LittleEndian>(query.exclude.len() as u32)?;
+            for id in &query.exclude {
+                w.write_u64::<LittleEndian>(*id)?;
+            }
+            w.write_u64::<LittleEndian>(query.sampled_stake)?;
+            w.write_u32::<LittleEndian>(query.round)?;
+        }
+        Message::QueryResponse((to, resp)) => {
+            w.write_u8(1)?;
+            w.write_u64::<LittleEndian>(*to)?;
+            write_response(w, resp)?;
+        }
+        Message::Transaction(tx) => {
+            w.write_u8(2)?;
+            tx.write_to(w)?;
+        }
+        Message::QueryBatch(batch) => {
+            w.write_u8(3)?;
+            w.write_u32::<LittleEndian>(batch.queries.len() as u32)?;
+            for (tx, status, round) in &batch.queries {
+                tx.write_to(w)?;
+                write_status(w, status)?;
+                w.write_u32::<LittleEndian>(*round)?;
+            }
+            w.write_u64::<LittleEndian>(batch.samples as u64)?;
+            w.write_u64::<LittleEndian>(batch.sampled_stake)?;
+        }
+        Message::QueryBatchResponse((to, resps)) => {
+            w.write_u8(4)?;
+            w.write_u64::<LittleEndian>(*to)?;
+            w.write_u32::<LittleEndian>(resps.len() as u32)?;
+            for resp in resps {
+                write_response(w, resp)?;
+            }
+        }
+        Message::Transactions(txs) => {
+            w.write_u8(5)?;
+            w.write_u32::<LittleEndian>(txs.len() as u32)?;
+            for tx in txs {
+                tx.write_to(w)?;
+            }
+        }
+        Message::Decided((to, hash, status)) => {
+            w.write_u8(6)?;
+            w.write_u64::<LittleEndian>(*to)?;
+            w.write_all(hash.as_bytes())?;
+            write_status(w, status)?;
+        }
+        Message::MempoolRequest => w.write_u8(7)?,
+        Message::MempoolDigest((to, hashes)) => {
+            w.write_u8(8)?;
+            w.write_u64::<LittleEndian>(*to)?;
+            write_hashes(w, hashes)?;
+        }
+        Message::Pull((to, hashes)) => {
+            w.write_u8(9)?;
+            w.write_u64::<LittleEndian>(*to)?;
+            write_hashes(w, hashes)?;
+        }
+        Message::Pulled((to, txs)) => {
+            w.write_u8(10)?;
+            w.write_u64::<LittleEndian>(*to)?;
+            w.write_u32::<LittleEndian>(txs.len() as u32)?;
+            for tx in txs {
+                tx.write_to(w)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn write_hashes<W: Write>(w: &mut W, hashes: &[Hash]) -> io::Result<()> {
+    w.write_u32::<LittleEndian>(hashes.len() as u32)?;
+    for hash in hashes {
+        w.write_all(hash.as_bytes())?;
+    }
+    Ok(())
+}
+
+fn read_hashes<R: Read>(r: &mut R) -> io::Result<Vec<Hash>> {
+    let n = r.read_u32::<LittleEndian>()?;
+    (0..n).map(|_| read_hash(r)).collect()
+}
+
+fn write_response<W: Write>(w: &mut W, resp: &QueryResponse) -> io::Result<()> {
+    w.write_all(resp.hash.as_bytes())?;
+    write_status(w, &resp.status)?;
+    w.write_u64::<LittleEndian>(resp.stake)?;
+    w.write_u64::<LittleEndian>(resp.sampled_stake)?;
+    w.write_u32::<LittleEndian>(resp.round)?;
+    match resp.progress {
+        Some(progress) => {
+            w.write_u8(1)?;
+            w.write_u32::<LittleEndian>(progress.epoch)?;
+            w.write_u32::<LittleEndian>(progress.cnt)
+        }
+        None => w.write_u8(0),
+    }
+}
+
+fn read_response<R: Read>(r: &mut R) -> io::Result<QueryResponse> {
+    let hash = read_hash(r)?;
+    let status = read_status(r)?;
+    let stake = r.read_u64::<LittleEndian>()?;
+    let sampled_stake = r.read_u64::<LittleEndian>()?;
+    let round = r.read_u32::<LittleEndian>()?;
+    let progress = match r.read_u8()? {
+        0 => None,
+        1 => Some(Progress {
+            epoch: r.read_u32::<LittleEndian>()?,
+            cnt: r.read_u32::<LittleEndian>()?,
+        }),
+        _ => return Err(invalid_data("invalid progress flag")),
+    };
+    Ok(QueryResponse {
+        hash,
+        status,
+        stake,
+        sampled_stake,
+        progress,
+        round,
+    })
+}
+
+fn read_message<R: Read>(r: &mut R) -> io::Result<Message> {
+    match r.read_u8()? {
+        0 => {
+            let tx = Transaction::read_from(r)?;
+            let status = read_status(r)?;
+            let samples = r.read_u64::<LittleEndian>()? as usize;
+            let n = r.read_u32::<LittleEndian>()?;
+            let exclude = (0..n)
+                .map(|_| r.read_u64::<LittleEndian>())
+                .collect::<io::Result<_>>()?;
+            let sampled_stake = r.read_u64::<LittleEndian>()?;
+            let round = r.read_u32::<LittleEndian>()?;
+            Ok(Message::Query(QueryMessage {
+                tx,
+                status,
+                samples,
+                exclude,
+                sampled_stake,
+                round,
+            }))
+        }
+        1 => {
+            let to = r.read_u64::<LittleEndian>()?;
+            Ok(Message::QueryResponse((to, read_response(r)?)))
+        }
+        2 => Ok(Message::Transaction(Transaction::read_from(r)?)),
+        3 => {
+            let n = r.read_u32::<LittleEndian>()?;
+            let queries = (0..n)
+                .map(|_| {
+                    let tx = Transaction::read_from(r)?;
+                    Ok((tx, read_status(r)?, r.read_u32::<LittleEndian>()?))
+                })
+                .collect::<io::Result<_>>()?;
+            let samples = r.read_u64::<LittleEndian>()? as usize;
+            let sampled_stake = r.read_u64::<LittleEndian>()?;
+            Ok(Message::QueryBatch(QueryBatch {
+                queries,
+                samples,
+                sampled_stake,
+            }))
+        }
+        4 => {
+            let to = r.read_u64::<LittleEndian>()?;
+            let n = r.read_u32::<LittleEndian>()?;
+            let resps = (0..n).map(|_| read_response(r)).collect::<io::Result<_>>()?;
+            Ok(Message::QueryBatchResponse((to, resps)))
+        }
+        5 => {
+            let n = r.read_u32::<LittleEndian>()?;
+            let txs = (0..n)
+                .map(|_| Transaction::read_from(r))
+                .collect::<io::Result<_>>()?;
+            Ok(Message::Transactions(txs))
+        }
+        6 => {
+            let to = r.read_u64::<LittleEndian>()?;
+            let hash = read_hash(r)?;
+            Ok(Message::Decided((to, hash, read_status(r)?)))
+        }
+        7 => Ok(Message::MempoolRequest),
+        8 => {
+            let to = r.read_u64::<LittleEndian>()?;
+            Ok(Message::MempoolDigest((to, read_hashes(r)?)))
+        }
+        9 => {
+            let to = r.read_u64::<LittleEndian>()?;
+            Ok(Message::Pull((to, read_hashes(r)?)))
+        }
+        10 => {
+            let to = r.read_u64::<LittleEndian>()?;
+            let n = r.read_u32::<LittleEndian>()?;
+            let txs = (0..n)
+                .map(|_| Transaction::read_from(r))
+                .collect::<io::Result<_>>()?;
+            Ok(Message::Pulled((to, txs)))
+        }
+        _ => Err(invalid_data("invalid message type")),
+    }
+}
+
+/// Source of the time that timeouts, ttls, latencies and the generator rate
+/// are measured with, so a simulation can run on a `MockClock`.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+    fn sleep(&self, duration: Duration);
+}
+
+impl ::std::fmt::Debug for dyn Clock {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "Clock")
+    }
+}
+
+/// The system clock, used unless another clock is set.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration)
+    }
+}
+
+fn system_clock() -> Arc<dyn Clock> {
+    Arc::new(SystemClock)
+}
+
+/// A clock that only moves when `advance` is called, to trigger timeouts
+/// and expiry deterministically. Sleeping returns right away without moving
+/// the clock. Clones share the same time.
+#[derive(Debug, Clone)]
+pub struct MockClock {
+    now: Arc<Mutex<Instant>>,
+}
+
+impl MockClock {
+    pub fn new() -> Self {
+        MockClock {
+            now: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    /// Move the clock forward by `duration`.
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        MockClock::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+
+    fn sleep(&self, _: Duration) {
+        thread::yield_now()
+    }
+}
+
+/// Latency model of the network. Every message is delayed by `base` plus a
+/// uniformly random jitter in `[0, jitter]`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Latency {
+    pub base: Duration,
+    pub jitter: Duration,
+}
+
+impl Latency {
+    pub fn new(base: Duration, jitter: Duration) -> Self {
+        Latency { base, jitter }
+    }
+
+    /// Sample the delay of a single message.
+    fn sample<R: Rng>(&self, rng: &mut R) -> Duration {
+        let jitter = self.jitter.as_nanos() as u64;
+        if jitter == 0 {
+            return self.base;
+        }
+        self.base + Duration::from_nanos(rng.gen_range(0, jitter + 1))
+    }
+}
+
+/// The peers every node knows and samples its queries from. Without explicit
+/// peers every node knows every other node (full mesh).
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Topology {
+    peers: Option<BTreeMap<u64, BTreeSet<u64>>>,
+}
+
+impl Topology {
+    /// Every node knows every other node.
+    pub fn full_mesh() -> Self {
+        Topology::default()
+    }
+
+    /// Nodes that only know the peers connected with `connect`.
+    pub fn empty() -> Self {
+        Topology {
+            peers: Some(BTreeMap::new()),
+        }
+    }
+
+    /// Connect every node to `degree` peers chosen uniformly at random. Since
+    /// connections go both ways most nodes end up with more than `degree`
+    /// peers.
+    pub fn random<R: Rng>(rng: &mut R, ids: &[u64], degree: usize) -> Self {
+        let mut topology = Topology::empty();
+        for id in ids {
+            let others = ids.iter().cloned().filter(|other| other != id);
+            let peers = seq::sample_iter(rng, others, degree).unwrap_or_else(|ids| ids);
+            for peer in peers {
+                topology.connect(*id, peer);
+            }
+        }
+        topology
+    }
+
+    /// Watts-Strogatz small world: the nodes form a ring in which every node
+    /// is connected to its `degree` nearest neighbours, then every connection
+    /// is rewired to a random node with probability `p`.
+    pub fn small_world<R: Rng>(rng: &mut R, ids: &[u64], degree: usize, p: f64) -> Self {
+        let mut topology = Topology::empty();
+        let n = ids.len();
+        for i in 0..n {
+            for j in 1..=(degree / 2).min(n.saturating_sub(1)) {
+                let mut peer = ids[(i + j) % n];
+                if rng.gen::<f64>() < p {
+                    let candidates = ids
+                        .iter()
+                        .cloned()
+                        .filter(|id| *id != ids[i] && !topology.knows(ids[i], *id))
+                        .collect::<Vec<_>>();
+                    if let Some(id) = rng.choose(&candidates) {
+                        peer = *id;
+                    }
+                }
+                topology.connect(ids[i], peer);
+            }
+        }
+        topology
+    }
+
+    /// Let `a` and `b` know each other.
+    pub fn connect(&mut self, a: u64, b: u64) {
+        if a == b {
+            return;
+        }
+        if let Some(ref mut peers) = self.peers {
+            peers.entry(a).or_default().insert(b);
+            peers.entry(b).or_default().insert(a);
+        }
+    }
+
+    /// Returns true if `a` knows `b`.
+    pub fn knows(&self, a: u64, b: u64) -> bool {
+        match self.peers {
+            Some(ref peers) => peers.get(&a).is_some_and(|peers| peers.contains(&b)),
+            None => a != b,
+        }
+    }
+
+    /// Returns the peers of node `id`, `None` if it knows every node.
+    pub fn peers(&self, id: u64) -> Option<Vec<u64>> {
+        self.peers.as_ref().map(|peers| {
+            peers
+                .get(&id)
+                .map_or_else(Vec::new, |peers| peers.iter().cloned().collect())
+        })
+    }
+}
+
+/// A message waiting in the dispatcher until its delivery time. Messages with
+/// the same delivery time become due in the order they were sent.
+#[derive(Debug)]
+struct Delayed {
+    at: Instant,
+    envelope: Envelope,
+}
+
+impl PartialEq for Delayed {
+    fn eq(&self, other: &Self) -> bool {
+        (self.at, self.envelope.seq) == (other.at, other.envelope.seq)
+    }
+}
+
+impl Eq for Delayed {}
+
+impl PartialOrd for Delayed {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Delayed {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.at, self.envelope.seq).cmp(&(other.at, other.envelope.seq))
+    }
+}
+
+/// Messages due for delivery, queued per sending node. Popping takes turns
+/// between the senders, so no node holds up the messages of the others.
+#[derive(Debug, Default)]
+struct FairQueue {
+    queues: BTreeMap<u64, VecDeque<Envelope>>,
+    /// Sender of the last popped message.
+    last: Option<u64>,
+}
+
+impl FairQueue {
+    fn push(&mut self, envelope: Envelope) {
+        self.queues
+            .entry(envelope.origin)
+            .or_default()
+            .push_back(envelope);
+    }
+
+    /// Pop the oldest message of the next sender after the last one.
+    fn pop(&mut self) -> Option<Envelope> {
+        let after = self.last.map_or(Bound::Unbounded, Bound::Excluded);
+        let origin = self
+            .queues
+            .range((after, Bound::Unbounded))
+            .chain(self.queues.iter())
+            .map(|(origin, _)| *origin)
+            .next()?;
+        let queue = self.queues.get_mut(&origin).unwrap();
+        let envelope = queue.pop_front();
+        if queue.is_empty() {
+            self.queues.remove(&origin);
+        }
+        self.last = Some(origin);
+        envelope
+    }
+}
+
+/// Two nodes that finalized the same transaction to different statuses.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SafetyViolation {
+    pub hash: Hash,
+    /// The node that finalized the transaction first and its status.
+    pub first: (u64, Status),
+    /// The node that finalized the transaction to a different status.
+    pub conflicting: (u64, Status),
+}
+
+/// Number of nodes out of `nodes` that must have finalized a transaction to
+/// the same status for the network to have decided on it.
+fn decision_quorum(nodes: usize) -> usize {
+    ((DECISION_QUORUM * nodes as f64).ceil() as usize).max(1)
+}
+
+/// Finalized decisions of all nodes of a network, keyed by the transaction
+/// with the first node that finalized it.
+#[derive(Debug, Default)]
+struct Ledger {
+    finalized: HashMap<Hash, (u64, Status)>,
+    violations: Vec<SafetyViolation>,
+    /// Number of nodes that finalized each transaction as valid and as
+    /// invalid.
+    tally: HashMap<Hash, (usize, usize)>,
+    /// Number of nodes in the network, see `decision_quorum`.
+    members: usize,
+    /// Submitters waiting for the network to decide on a transaction, see
+    /// `Network::inject`.
+    waiters: HashMap<Hash, Vec<Sender<Status>>>,
+}
+
+impl Ledger {
+    /// Records the decision of a node, checking it against the first
+    /// finalization of the same transaction.
+    fn record(&mut self, node: u64, hash: &Hash, status: &Status) -> Option<SafetyViolation> {
+        self.count(hash, status);
+        self.notify(hash);
+        let first = self
+            .finalized
+            .entry(hash.clone())
+            .or_insert_with(|| (node, status.clone()));
+        if first.1 == *status {
+            return None;
+        }
+        let violation = SafetyViolation {
+            hash: hash.clone(),
+            first: first.clone(),
+            conflicting: (node, status.clone()),
+        };
+        self.violations.push(violation.clone());
+        Some(violation)
+    }
+
+    /// Count a node finalizing the transaction to `status`.
+    fn count(&mut self, hash: &Hash, status: &Status) {
+        let tally = self.tally.entry(hash.clone()).or_default();
+        match status {
+            Status::Valid => tally.0 += 1,
+            Status::Invalid => tally.1 += 1,
+            Status::Unknown => {}
+        }
+    }
+
+    /// Returns the status a `decision_quorum` of the nodes finalized the
+    /// transaction to, if any.
+    fn decision(&self, hash: &Hash) -> Option<Status> {
+        let needed = decision_quorum(self.members);
+        match self.tally.get(hash) {
+            Some((valid, _)) if *valid >= needed => Some(Status::Valid),
+            Some((_, invalid)) if *invalid >= needed => Some(Status::Invalid),
+            _ => None,
+        }
+    }
+
+    /// Returns a receiver of the decision on the transaction, which gets it
+    /// right away if the network already decided.
+    fn wait_for(&mut self, hash: &Hash) -> Receiver<Status> {
+        let (sender, receiver) = channel();
+        self.waiters.entry(hash.clone()).or_default().push(sender);
+        self.notify(hash);
+        receiver
+    }
+
+    /// Hand the decision on the transaction to everybody waiting for it, once
+    /// the network decided.
+    fn notify(&mut self, hash: &Hash) {
+        if !self.waiters.contains_key(hash) {
+            return;
+        }
+        if let Some(status) = self.decision(hash) {
+            for waiter in self.waiters.remove(hash).unwrap() {
+                // The submitter is no longer interested.
+                let _ = waiter.send(status.clone());
+            }
+        }
+    }
+
+    /// Change the number of nodes in the network. Fewer nodes can make a
+    /// transaction decided.
+    fn set_members(&mut self, members: usize) {
+        self.members = members;
+        let waiting = self.waiters.keys().cloned().collect::<Vec<_>>();
+        for hash in waiting {
+            self.notify(&hash);
+        }
+    }
+}
+
+/// The decision of the network on a transaction handed to `Network::inject`.
+/// It arrives once a `DECISION_QUORUM` fraction of the nodes finalized the
+/// transaction to the same status, and is handed out only once.
+#[derive(Debug)]
+pub struct Decision {
+    hash: Hash,
+    receiver: Receiver<Status>,
+}
+
+impl Decision {
+    /// The hash of the transaction.
+    pub fn hash(&self) -> &Hash {
+        &self.hash
+    }
+
+    /// Block until the network decided on the transaction. Returns an error
+    /// if the network is dropped before.
+    pub fn wait(&self) -> Result<Status> {
+        self.receiver.recv().map_err(|_| AvalancheError::Disconnected)
+    }
+
+    /// Like `wait` but gives up after `timeout`.
+    pub fn wait_timeout(&self, timeout: Duration) -> Result<Status> {
+        match self.receiver.recv_timeout(timeout) {
+            Ok(status) => Ok(status),
+            Err(RecvTimeoutError::Timeout) => Err(AvalancheError::Timeout(timeout)),
+            Err(RecvTimeoutError::Disconnected) => Err(AvalancheError::Disconnected),
+        }
+    }
+
+    /// Returns the decision without blocking, `None` if the network did not
+    /// decide yet.
+    pub fn try_wait(&self) -> Result<Option<Status>> {
+        match self.receiver.try_recv() {
+            Ok(status) => Ok(Some(status)),
+            Err(TryRecvError::Empty) => Ok(None),
+            Err(TryRecvError::Disconnected) => Err(AvalancheError::Disconnected),
+        }
+    }
+}
+
+/// Finalized decisions shared by all nodes of a network.
+type Decisions = Arc<Mutex<Ledger>>;
+
+/// Progress of a single node on a single transaction, reported to the
+/// callbacks registered with `Network::on_event`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// The node queried a sample of its peers with its current color.
+    QuerySent { node: u64, hash: Hash, status: Status },
+    /// The node received a response with the color of a peer.
+    ResponseReceived { node: u64, hash: Hash, status: Status },
+    /// A quorum made the node change its color.
+    ColorFlipped { node: u64, hash: Hash, status: Status },
+    /// The node accepted its color for an epoch and moved on to `epoch`.
+    EpochAdvanced {
+        node: u64,
+        hash: Hash,
+        status: Status,
+        epoch: u32,
+    },
+    /// The node finalized the transaction with the given status, followed
+    /// by `Accepted` or `Rejected`.
+    Finalized { node: u64, hash: Hash, status: Status },
+    /// The node finalized the transaction as valid, e.g. to apply it to a
+    /// ledger.
+    Accepted { node: u64, tx: Transaction },
+    /// The node finalized the transaction as invalid, it can be discarded.
+    /// `reason` is why the node found it invalid itself, `None` if it only
+    /// followed its peers.
+    Rejected {
+        node: u64,
+        tx: Transaction,
+        reason: Option<InvalidReason>,
+    },
+    /// The node gave up on the transaction after its ttl, undecided.
+    Expired { node: u64, hash: Hash, status: Status },
+}
+
+type Observer = Box<dyn Fn(&Event) + Send + Sync>;
+
+/// Callbacks registered with `Network::on_event`, shared by all nodes.
+#[derive(Clone, Default)]
+struct Observers(Arc<RwLock<Vec<Observer>>>);
+
+impl Observers {
+    /// Report the event to every observer. The event is only built if there
+    /// is anybody observing.
+    fn emit<F: FnOnce() -> Event>(&self, event: F) {
+        let observers = self.0.read().unwrap();
+        if observers.is_empty() {
+            return;
+        }
+        let event = event();
+        for observer in observers.iter() {
+            observer(&event);
+        }
+    }
+}
+
+impl ::std::fmt::Debug for Observers {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "Observers({})", self.0.read().unwrap().len())
+    }
+}
+
+/// Finalization latencies collected over all nodes of a network.
+#[derive(Debug, Clone, Default)]
+pub struct LatencyHistogram {
+    /// All samples, sorted ascending.
+    samples: Vec<Duration>,
+}
+
+impl LatencyHistogram {
+    pub fn new(mut samples: Vec<Duration>) -> Self {
+        samples.sort();
+        LatencyHistogram { samples }
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    /// Returns the latency below which `p` percent of the samples fall.
+    pub fn percentile(&self, p: f64) -> Option<Duration> {
+        if self.samples.is_empty() {
+            return None;
+        }
+        let rank = (p / 100.0 * self.samples.len() as f64).ceil() as usize;
+        let idx = rank.max(1).min(self.samples.len()) - 1;
+        Some(self.samples[idx])
+    }
+
+    pub fn p50(&self) -> Option<Duration> {
+        self.percentile(50.0)
+    }
+
+    pub fn p99(&self) -> Option<Duration> {
+        self.percentile(99.0)
+    }
+
+    pub fn max(&self) -> Option<Duration> {
+        self.samples.last().cloned()
+    }
+}
+
+/// Number of messages delivered for a single transaction.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MessageCount {
+    pub queries: u64,
+    pub responses: u64,
+}
+
+impl MessageCount {
+    pub fn total(&self) -> u64 {
+        self.queries + self.responses
+    }
+}
+
+/// Query and response messages delivered in a network, per transaction.
+#[derive(Debug, Clone, Default)]
+pub struct MessageStats {
+    counts: HashMap<Hash, MessageCount>,
+}
+
+impl MessageStats {
+    fn count(&mut self, msg: &Message) {
+        match msg {
+            Message::Query(query) => {
+                self.counts.entry(query.tx.id().clone()).or_default().queries += 1
+            }
+            Message::QueryResponse((_, resp)) => {
+                self.counts.entry(resp.hash.clone()).or_default().responses += 1
+            }
+            Message::QueryBatch(batch) => {
+                for (tx, _, _) in &batch.queries {
+                    self.counts.entry(tx.id().clone()).or_default().queries += 1
+                }
+            }
+            Message::QueryBatchResponse((_, resps)) => {
+                for resp in resps {
+                    self.counts.entry(resp.hash.clone()).or_default().responses += 1
+                }
+            }
+            Message::Decided((_, hash, _)) => {
+                self.counts.entry(hash.clone()).or_default().responses += 1
+            }
+            Message::Transaction(_)
+            | Message::Transactions(_)
+            | Message::MempoolRequest
+            | Message::MempoolDigest(_)
+            | Message::Pull(_)
+            | Message::Pulled(_) => {}
+        }
+    }
+
+    /// Returns the messages delivered for the given transaction.
+    pub fn get(&self, hash: &Hash) -> MessageCount {
+        self.counts.get(hash).cloned().unwrap_or_default()
+    }
+
+    /// Returns the messages delivered over all transactions.
+    pub fn total(&self) -> MessageCount {
+        self.counts.values().fold(MessageCount::default(), |acc, c| MessageCount {
+            queries: acc.queries + c.queries,
+            responses: acc.responses + c.responses,
+        })
+    }
+
+    /// Iterate over the message counts of every transaction.
+    pub fn iter(&self) -> impl Iterator<Item = (&Hash, &MessageCount)> {
+        self.counts.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.counts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.counts.is_empty()
+    }
+}
+
+/// Overall state of a network, see `Network::stats`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NetworkStats {
+    /// Query and response messages delivered over all transactions.
+    pub messages: MessageCount,
+    /// Messages dropped because the network was saturated.
+    pub dropped_messages: usize,
+    /// Number of transactions finalized by at least one node, not counting
+    /// the genesis.
+    pub finalized: usize,
+    /// Average epoch nodes finalized their transactions in, over every node
+    /// and transaction. `None` while no node finalized anything.
+    pub avg_epochs: Option<f64>,
+    /// Number of transactions in the mempool of every node by id, including
+    /// the genesis.
+    pub mempool_sizes: BTreeMap<u64, usize>,
+    pub safety_violations: Vec<SafetyViolation>,
+}
+
+/// A node a query can be sent to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Candidate {
+    pub id: u64,
+    pub stake: u64,
+}
+
+/// Picks the nodes a query is sent to, see `Network::set_sampling`.
+pub trait SamplingStrategy: Send + Sync {
+    /// Sample up to `k` of the `candidates` for a query of node `origin`. The
+    /// candidates are the nodes `origin` knows in the topology of the
+    /// network, without `origin` itself. Nodes in `exclude` already responded
+    /// in the current round and must not be sampled again, the network drops
+    /// them from the sample as well as nodes that are no candidates.
+    fn sample(
+        &self,
+        rng: &mut dyn RngCore,
+        origin: u64,
+        candidates: &[Candidate],
+        k: usize,
+        exclude: &[u64],
+    ) -> Vec<u64>;
+}
+
+impl ::std::fmt::Debug for dyn SamplingStrategy {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "SamplingStrategy")
+    }
+}
+
+/// Samples every candidate with the same probability, whatever its stake.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Uniform;
+
+impl SamplingStrategy for Uniform {
+    fn sample(
+        &self,
+        rng: &mut dyn RngCore,
+        _: u64,
+        candidates: &[Candidate],
+        k: usize,
+        exclude: &[u64],
+    ) -> Vec<u64> {
+        let ids = candidates
+            .iter()
+            .map(|candidate| candidate.id)
+            .filter(|id| !exclude.contains(id));
+        seq::sample_iter(rng, ids, k).unwrap_or_else(|ids| ids)
+    }
+}
+
+/// Samples `k` candidates without replacement, with a probability
+/// proportional to their stake. This is the default. Every node gets the
+/// key `u^(1/stake)` for a uniform random `u` and the nodes with the `k`
+/// largest keys are sampled (Efraimidis-Spirakis). Nodes without stake are
+/// never sampled. With fewer than `k` candidates all of them are sampled,
+/// which is why quorums are based on the stake of the actual sample
+/// (`sampled_stake`) rather than on `k`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StakeWeighted;
+
+impl SamplingStrategy for StakeWeighted {
+    fn sample(
+        &self,
+        rng: &mut dyn RngCore,
+        _: u64,
+        candidates: &[Candidate],
+        k: usize,
+        exclude: &[u64],
+    ) -> Vec<u64> {
+        let mut keyed: Vec<(f64, u64)> = candidates
+            .iter()
+            .filter(|candidate| candidate.stake > 0 && !exclude.contains(&candidate.id))
+            .map(|candidate| {
+                let key = rng.gen::<f64>().powf(1.0 / candidate.stake as f64);
+                (key, candidate.id)
+            })
+            .collect();
+        keyed.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(Ordering::Equal));
+        keyed.into_iter().take(k).map(|(_, id)| id).collect()
+    }
+}
+
+/// Samples with `inner` among the peers the querying node knows in
+/// `topology` only, on top of the topology of the network. E.g. to let a
+/// few nodes sample a neighborhood while the others sample everybody.
+#[derive(Debug, Clone)]
+pub struct TopologyConstrained {
+    pub topology: Topology,
+    pub inner: Arc<dyn SamplingStrategy>,
+}
+
+impl TopologyConstrained {
+    pub fn new(topology: Topology, inner: Arc<dyn SamplingStrategy>) -> Self {
+        TopologyConstrained { topology, inner }
+    }
+}
+
+impl SamplingStrategy for TopologyConstrained {
+    fn sample(
+        &self,
+        rng: &mut dyn RngCore,
+        origin: u64,
+        candidates: &[Candidate],
+        k: usize,
+        exclude: &[u64],
+    ) -> Vec<u64> {
+        let known = candidates
+            .iter()
+            .filter(|candidate| self.topology.knows(origin, candidate.id))
+            .cloned()
+            .collect::<Vec<_>>();
+        self.inner.sample(rng, origin, &known, k, exclude)
+    }
+}
+
+/// Samples the `controlled` nodes first and only fills the rest of the
+/// sample with `inner`, like an adversary eclipsing the nodes it serves.
+#[derive(Debug, Clone)]
+pub struct Adversarial {
+    pub controlled: BTreeSet<u64>,
+    pub inner: Arc<dyn SamplingStrategy>,
+}
+
+impl Adversarial {
+    pub fn new(controlled: BTreeSet<u64>, inner: Arc<dyn SamplingStrategy>) -> Self {
+        Adversarial { controlled, inner }
+    }
+}
+
+impl SamplingStrategy for Adversarial {
+    fn sample(
+        &self,
+        rng: &mut dyn RngCore,
+        origin: u64,
+        candidates: &[Candidate],
+        k: usize,
+        exclude: &[u64],
+    ) -> Vec<u64> {
+        let (controlled, honest): (Vec<Candidate>, Vec<Candidate>) = candidates
+            .iter()
+            .partition(|candidate| self.controlled.contains(&candidate.id));
+        let mut sampled = self.inner.sample(rng, origin, &controlled, k, exclude);
+        let rest = k.saturating_sub(sampled.len());
+        sampled.extend(self.inner.sample(rng, origin, &honest, rest, exclude));
+        sampled
+    }
+}
+
+/// How queries pick the nodes they are sent to.
+#[derive(Clone, Copy)]
+struct Sampler<'a> {
+    topology: &'a Topology,
+    strategy: &'a dyn SamplingStrategy,
+}
+
+impl<'a> Sampler<'a> {
+    /// Sample `n` peers `origin` knows, never `origin` itself or the nodes
+    /// in `exclude`, whatever the strategy returns.
+    fn sample(
+        &self,
+        rng: &mut dyn RngCore,
+        nodes: &Nodes,
+        origin: u64,
+        exclude: &[u64],
+        n: usize,
+    ) -> Vec<u64> {
+        // Nodes that panicked are out of the network for good.
+        let candidates = nodes
+            .iter()
+            .filter(|(id, _)| **id != origin && self.topology.knows(origin, **id))
+            .filter_map(|(id, node)| match node.lock() {
+                Ok(ref node) if !node.failed => Some(Candidate {
+                    id: *id,
+                    stake: node.stake,
+                }),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let mut sampled = self.strategy.sample(rng, origin, &candidates, n, exclude);
+        let mut seen = HashSet::new();
+        sampled.retain(|id| {
+            !exclude.contains(id)
+                && candidates.iter().any(|candidate| candidate.id == *id)
+                && seen.insert(*id)
+        });
+        sampled.truncate(n);
+        sampled
+    }
+}
+
+fn stake_of(nodes: &Nodes, id: u64) -> u64 {
+    nodes[&id].lock().map_or(0, |node| node.stake)
+}
+
+/// Extra delay of the envelope if it is a response of a slow node.
+fn response_delay(nodes: &Nodes, envelope: &Envelope) -> Duration {
+    match envelope.msg {
+        Message::QueryResponse(_) | Message::QueryBatchResponse(_) | Message::Decided(_) => nodes
+            .get(&envelope.origin)
+            .map_or(Duration::from_secs(0), |node| lock_node(node).response_delay),
+        _ => Duration::from_secs(0),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TxState {
+    epoch: u32,
+    tx: Transaction,
+    status: Status,
+    /// Status and stake of the response of every node that responded in the
+    /// current round. Every node votes at most once per round, and responses
+    /// to the query of another round are dropped, so no response counts
+    /// towards the quorum of a later round or epoch.
+    responses: BTreeMap<u64, (Status, u64)>,
+    /// Round of the query the current responses answer, see `Node::round`.
+    round: u32,
+    /// Total stake of the sample of the current round, as reported by its
+    /// latest response. The round ends once all of it responded.
+    sampled_stake: u64,
+    /// Number of responders of the current round per epoch they are in, only
+    /// kept with `ConsensusParams::diagnostics`.
+    peer_epochs: BTreeMap<u32, usize>,
+    /// Every response received over all epochs, only kept with
+    /// `ConsensusParams::vote_history`.
+    votes: Vec<Vote>,
+    is_final: bool,
+    /// Set once the transaction outlived the ttl without being finalized.
+    is_expired: bool,
+    /// Why we colored the transaction invalid when we first saw it.
+    reason: Option<InvalidReason>,
+
+    /// 1. Each node maintains a counter cnt
+    /// 2. Upon every color change, the node resets cnt to 0
+    /// 3. Upon every successful query that yields ≥ αk responses for the same
+    ///    color as the node, the node increments cnt.
+    cnt_valid: u32,
+    cnt_invalid: u32,
+    cnt: u32,
+    /// Quorums received over all epochs. The first epoch takes more than
+    /// `conviction_treshold` of them and every further epoch at least one
+    /// more, which `step` checks on every accepted epoch.
+    quorums: u32,
+
+    /// Time the outstanding query of the current epoch was sent.
+    queried_at: Instant,
+
+    /// Time the transaction entered the mempool.
+    created_at: Instant,
+    /// Time the transaction was finalized.
+    finalized_at: Option<Instant>,
+    clock: Arc<dyn Clock>,
+
+    params: ConsensusParams,
+}
+
+impl TxState {
+    /// Create the state of a transaction a node just learned about, colored
+    /// with `status`.
+    pub fn new(tx: Transaction, status: Status, params: ConsensusParams) -> Self {
+        TxState {
+            responses: BTreeMap::new(),
+            round: 0,
+            sampled_stake: 0,
+            peer_epochs: BTreeMap::new(),
+            votes: Vec::new(),
+            is_final: false,
+            is_expired: false,
+            reason: None,
+            epoch: 0,
+            cnt_valid: 0,
+            cnt_invalid: 0,
+            cnt: 0,
+            quorums: 0,
+            queried_at: Instant::now(),
+            created_at: Instant::now(),
+            finalized_at: None,
+            clock: system_clock(),
+            tx,
+            status,
+            params,
+        }
+    }
+
+    /// Start from the given epoch, e.g. to preload a node mid-protocol.
+    pub fn with_epoch(mut self, epoch: u32) -> Self {
+        self.epoch = epoch;
+        // As if every epoch was accepted with as few quorums as possible.
+        if epoch > 0 {
+            let quorums = self.params.conviction_treshold().saturating_add(epoch);
+            self.quorums = self.quorums.max(quorums);
+        }
+        self
+    }
+
+    /// Measure time on `clock`, the state counts as created and queried at
+    /// the current time of the clock.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.created_at = clock.now();
+        self.queried_at = self.created_at;
+        self.clock = clock;
+        self
+    }
+
+    /// Start with `cnt` consecutive quorums for the current color.
+    pub fn with_cnt(mut self, cnt: u32) -> Self {
+        self.cnt = cnt;
+        self.quorums = self.quorums.max(cnt);
+        self
+    }
+
+    /// Start with the confidence of both colors.
+    pub fn with_confidence(mut self, cnt_valid: u32, cnt_invalid: u32) -> Self {
+        self.cnt_valid = cnt_valid;
+        self.cnt_invalid = cnt_invalid;
+        self
+    }
+
+    /// Start with a response of node `id` already received in the current
+    /// round.
+    pub fn with_response(mut self, id: u64, status: Status, stake: u64) -> Self {
+        self.responses.insert(id, (status, stake));
+        self
+    }
+
+    fn record_vote(&mut self, responder: u64, status: &Status) {
+        if self.params.vote_history {
+            self.votes.push(Vote {
+                responder,
+                status: status.clone(),
+                epoch: self.epoch,
+            });
+        }
+    }
+
+    /// Counters saturate, a transaction stuck for a very long time must not
+    /// crash the node.
+    fn incr_status(&mut self, s: &Status) -> u32 {
+        match s {
+            Status::Valid => {
+                self.cnt_valid = self.cnt_valid.saturating_add(1);
+                self.cnt_valid
+            }
+            Status::Invalid => {
+                self.cnt_invalid = self.cnt_invalid.saturating_add(1);
+                self.cnt_invalid
+            }
+            Status::Unknown => 0,
+        }
+    }
+
+    fn status_count(&self, s: &Status) -> u32 {
+        match s {
+            Status::Valid => self.cnt_valid,
+            Status::Invalid => self.cnt_invalid,
+            Status::Unknown => 0,
+        }
+    }
+
+    pub fn tx(&self) -> &Transaction {
+        &self.tx
+    }
+
+    pub fn epoch(&self) -> u32 {
+        self.epoch
+    }
+
+    /// The current preference of the node.
+    pub fn status(&self) -> &Status {
+        &self.status
+    }
+
+    pub fn is_final(&self) -> bool {
+        self.is_final
+    }
+
+    /// Why the node colored the transaction invalid when it first saw it.
+    /// `None` if it considered the transaction valid or adopted the color of
+    /// a query.
+    pub fn invalid_reason(&self) -> Option<InvalidReason> {
+        self.reason
+    }
+
+    /// Status and stake of the responses counted towards the quorum of the
+    /// current round by responder, empty right after a round ends.
+    pub fn epoch_responses(&self) -> &BTreeMap<u64, (Status, u64)> {
+        &self.responses
+    }
+
+    /// Round of the query whose responses count towards the current quorum,
+    /// responses of any other round are dropped.
+    pub fn round(&self) -> u32 {
+        self.round
+    }
+
+    /// Total stake of the nodes sampled in the current round, which can be
+    /// less than `k` in small networks or sparse topologies. 0 until the
+    /// first response of the round arrives.
+    pub fn sampled_stake(&self) -> u64 {
+        self.sampled_stake
+    }
+
+    /// The conviction counter of the current preference.
+    pub fn cnt(&self) -> u32 {
+        self.cnt
+    }
+
+    /// Confidence in `Status::Valid`.
+    pub fn cnt_valid(&self) -> u32 {
+        self.cnt_valid
+    }
+
+    /// Confidence in `Status::Invalid`.
+    pub fn cnt_invalid(&self) -> u32 {
+        self.cnt_invalid
+    }
+
+    /// Time it took to finalize the transaction, `None` while it is not final.
+    fn latency(&self) -> Option<Duration> {
+        self.finalized_at
+            .map(|at| at.duration_since(self.created_at))
+    }
+
+    /// Consensus step on a quorum for `status`. Returns true once the
+    /// transaction is final.
+    fn step(&mut self, status: &Status) -> bool {
+        // A decided transaction must never be stepped again, its color could
+        // still flip.
+        debug_assert!(!self.is_final, "tx {} stepped after it was final", self.tx.id());
+        self.quorums = self.quorums.saturating_add(1);
+        let epoch = self.epoch;
+        let is_final = match self.params.mode {
+            ConsensusMode::Snowflake => self.snowflake(status),
+            ConsensusMode::Snowball => self.snowball(status),
+        };
+        // Catch counter logic that accepts an epoch without a full conviction
+        // behind it, or finalizes before `beta` epochs.
+        let treshold = self.params.conviction_treshold();
+        debug_assert!(
+            self.epoch == epoch || self.quorums >= treshold.saturating_add(self.epoch),
+            "tx {} accepted epoch {} after {} quorums, expected at least {}",
+            self.tx.id(),
+            self.epoch,
+            self.quorums,
+            treshold.saturating_add(self.epoch)
+        );
+        debug_assert!(
+            !is_final || self.epoch >= self.params.beta,
+            "tx {} finalized in epoch {}, expected at least {}",
+            self.tx.id(),
+            self.epoch,
+            self.params.beta
+        );
+        is_final
+    }
+
+    fn finalize(&mut self) {
+        self.is_final = true;
+        self.finalized_at = Some(self.clock.now());
+    }
+
+    /// Mark the transaction expired if it is still undecided after the ttl.
+    /// Returns true if it just expired.
+    fn expire(&mut self) -> bool {
+        let outlived = self
+            .params
+            .ttl
+            .is_some_and(|ttl| self.elapsed(self.created_at) >= ttl);
+        if self.is_final || self.is_expired || !outlived {
+            return false;
+        }
+        self.is_expired = true;
+        true
+    }
+
+    /// Snowflake step on a quorum for `status`. Returns true once the
+    /// transaction is final.
+    fn snowflake(&mut self, status: &Status) -> bool {
+        if *status != self.status {
+            self.status = status.clone();
+            self.cnt = 0;
+            return false;
+        }
+        self.convince()
+    }
+
+    /// Snowball step on a quorum for `status`. Returns true once the
+    /// transaction is final.
+    fn snowball(&mut self, status: &Status) -> bool {
+        // Increment the confidence of the received status.
+        let cnt = self.incr_status(status);
+        // Get the confidence of our current status.
+        let our_status_cnt = self.status_count(&self.status);
+
+        // If the confidence of the received status is higher then ours we
+        // flip to that status, which restarts the conviction counter. On a
+        // tie every node flips to the same color, see `tie_break`.
+        let tie = cnt == our_status_cnt && *status == self.tie_break();
+        if cnt > our_status_cnt || (tie && *status != self.status) {
+            self.status = status.clone();
+            self.cnt = 0;
+            return false;
+        }
+
+        // A quorum for the other status that is not enough to flip neither
+        // convinces us nor resets the counter.
+        if *status != self.status {
+            return false;
+        }
+        self.convince()
+    }
+
+    /// The color preferred by every node when both colors gathered equally
+    /// many quorums, derived from the hash so all nodes agree on it.
+    fn tie_break(&self) -> Status {
+        match self.tx.id().0[0] & 1 {
+            0 => Status::Valid,
+            _ => Status::Invalid,
+        }
+    }
+
+    /// Increment the conviction counter. We only accept the color (move to
+    /// the next epoch) if the counter is higher the the conviction treshold.
+    /// Returns true once the transaction is final.
+    ///
+    /// Epochs count from 0 and `advance` runs before the check, so a
+    /// transaction is final after exactly `beta` accepted epochs: with the
+    /// defaults the 4th accepted epoch finalizes it. The counter is not reset
+    /// by an epoch, so after the first accepted epoch every further quorum
+    /// for the same color accepts another one.
+    fn convince(&mut self) -> bool {
+        self.cnt = self.cnt.saturating_add(1);
+        if self.cnt > self.params.conviction_treshold() {
+            self.advance();
+            // A state preloaded past `beta` finalizes on its next accepted
+            // epoch instead of never.
+            return self.epoch >= self.params.beta;
+        }
+        false
+    }
+
+    /// Accept the color for the current epoch.
+    fn advance(&mut self) {
+        self.epoch = self.epoch.saturating_add(1);
+    }
+
+    /// Start a new query round, forgetting the responses of the last one.
+    fn next_round(&mut self) {
+        self.responses.clear();
+        self.sampled_stake = 0;
+        self.peer_epochs.clear();
+        self.queried_at = self.clock.now();
+    }
+
+    /// Time passed on our clock since `at`.
+    fn elapsed(&self, at: Instant) -> Duration {
+        self.clock.now().saturating_duration_since(at)
+    }
+}
+
+/// The view of a single node on a transaction, see `Node::query_confidence`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Confidence {
+    /// The color the node currently prefers.
+    pub status: Status,
+    /// Consecutive quorums for `status` in the current epoch.
+    pub cnt: u32,
+    /// Number of epochs the node accepted its color for.
+    pub epoch: u32,
+    pub is_final: bool,
+    /// The node stopped querying the transaction undecided, see
+    /// `ConsensusParams::ttl`.
+    pub is_expired: bool,
+}
+
+/// Decides whether a transaction is valid on its own, before consensus.
+/// Returning `Status::Unknown` leaves the node uncolored until it adopts the
+/// color of its peers.
+pub trait Validator: Send + Sync {
+    fn validate(&self, tx: &Transaction) -> Status;
+
+    /// Like `validate`, along with the reason an invalid transaction is
+    /// invalid. Defaults to `InvalidReason::Validator`.
+    fn verify(&self, tx: &Transaction) -> (Status, Option<InvalidReason>) {
+        let status = self.validate(tx);
+        let reason = match status {
+            Status::Invalid => Some(InvalidReason::Validator),
+            _ => None,
+        };
+        (status, reason)
+    }
+}
+
+impl ::std::fmt::Debug for dyn Validator {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "Validator")
+    }
+}
+
+impl<F> Validator for F
+where
+    F: Fn(&Transaction) -> Status + Send + Sync,
+{
+    fn validate(&self, tx: &Transaction) -> Status {
+        self(tx)
+    }
+}
+
+/// Accepts every transaction whose data is below the limit. All transactions
+/// with a number < `VALID_DATA_LIMIT` are valid by default.
+#[derive(Debug, Clone, Copy)]
+pub struct DataLimit(pub i32);
+
+impl Default for DataLimit {
+    fn default() -> Self {
+        DataLimit(VALID_DATA_LIMIT)
+    }
+}
+
+impl Validator for DataLimit {
+    fn validate(&self, tx: &Transaction) -> Status {
+        match tx.data < self.0 {
+            true => Status::Valid,
+            false => Status::Invalid,
+        }
+    }
+
+    fn verify(&self, tx: &Transaction) -> (Status, Option<InvalidReason>) {
+        match self.validate(tx) {
+            Status::Invalid => (Status::Invalid, Some(InvalidReason::DataOverLimit)),
+            status => (status, None),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Node {
+    mempool: HashMap<Hash, TxState>,
+    /// All transactions known per conflict id, in the order they were seen.
+    conflict_sets: HashMap<ConflictId, Vec<Hash>>,
+    id: u64,
+    sender: Outbox,
+    params: ConsensusParams,
+    /// Time after which missing query responses are re-sampled.
+    query_timeout: Duration,
+    /// When we sent the query of the current round of every transaction,
+    /// and its round. Kept across evictions, so a transaction seen again
+    /// before the round ended isn't queried twice, see `queue_query`.
+    queried: HashMap<Hash, (Instant, u32)>,
+    /// The latest round we started on any transaction. Rounds are numbered
+    /// across transactions, so a response to an earlier query never matches
+    /// a later one, even of a transaction that was evicted in between.
+    round: u32,
+    behavior: Behavior,
+    /// Weight of the node in sampling and quorums.
+    stake: u64,
+    /// Extra delay of every response the node sends, see
+    /// `Network::set_response_delay`.
+    response_delay: Duration,
+    rng: SimRng,
+    /// Decisions of the network this node reports its decisions to.
+    decisions: Decisions,
+    observers: Observers,
+    /// Rules the node verifies new transactions with.
+    validator: Arc<dyn Validator>,
+    clock: Arc<dyn Clock>,
+    /// Set once the node panicked while handling a message, see `guarded`.
+    failed: bool,
+}
+
+impl Node {
+    fn new(
+        id: u64,
+        sender: Outbox,
+        params: ConsensusParams,
+        rng: SimRng,
+        decisions: Decisions,
+        observers: Observers,
+    ) -> Self {
+        let mut node = Node {
+            id,
+            sender,
+            params,
+            rng,
+            decisions,
+            observers,
+            mempool: HashMap::new(),
+            conflict_sets: HashMap::new(),
+            query_timeout: QUERY_TIMEOUT,
+            queried: HashMap::new(),
+            round: 0,
+            behavior: Behavior::Honest,
+            stake: 1,
+            response_delay: Duration::from_secs(0),
+            validator: Arc::new(DataLimit::default()),
+            clock: system_clock(),
+            failed: false,
+        };
+        // Every node starts out agreeing on the genesis, without ever
+        // querying for it. It stays out of the conflict sets, so it doesn't
+        // conflict with transactions that happen to share its conflict id.
+        let genesis = Transaction::genesis();
+        let hash = genesis.id().clone();
+        let mut state = TxState::new(genesis, Status::Valid, params).with_epoch(params.beta);
+        state.is_final = true;
+        node.decisions.lock().unwrap().record(id, &hash, &Status::Valid);
+        node.mempool.insert(hash, state);
+        node
+    }
+
+    /// Create a node outside of a network whose mempool is preloaded with
+    /// the given states, to drive `handle_message` from a known state.
+    /// Messages the node sends go to `sender`.
+    pub fn new_with_mempool(
+        id: u64,
+        sender: Sender<Envelope>,
+        params: ConsensusParams,
+        mempool: Vec<TxState>,
+    ) -> Self {
+        let (decisions, observers) = (Decisions::default(), Observers::default());
+        let sender = Outbox::from(sender);
+        let mut node = Node::new(id, sender, params, seeded_rng(id), decisions, observers);
+        for state in mempool {
+            let hash = state.tx.id().clone();
+            node.conflict_sets
+                .entry(state.tx.conflict_id)
+                .or_default()
+                .push(hash.clone());
+            node.mempool.insert(hash, state);
+        }
+        node
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn behavior(&self) -> Behavior {
+        self.behavior
+    }
+
+    pub fn stake(&self) -> u64 {
+        self.stake
+    }
+
+    pub fn response_delay(&self) -> Duration {
+        self.response_delay
+    }
+
+    /// Returns true if the node panicked while handling a message. A failed
+    /// node is no longer sampled and ignores every message.
+    pub fn is_failed(&self) -> bool {
+        self.failed
+    }
+
+    /// Number of transactions in the mempool of this node, including the
+    /// genesis.
+    pub fn mempool_len(&self) -> usize {
+        self.mempool.len()
+    }
+
+    /// Returns the state of the given transaction, if this node knows it.
+    pub fn tx_state(&self, hash: &Hash) -> Option<&TxState> {
+        self.mempool.get(hash)
+    }
+
+    /// Returns how far this node got deciding on the given transaction, for
+    /// clients asking whether their transaction is accepted yet. `None` if
+    /// the node does not know the transaction.
+    pub fn query_confidence(&self, hash: &Hash) -> Option<Confidence> {
+        self.mempool.get(hash).map(|state| Confidence {
+            status: state.status.clone(),
+            cnt: state.cnt,
+            epoch: state.epoch,
+            is_final: state.is_final,
+            is_expired: state.is_expired,
+        })
+    }
+
+    /// Returns the color this node would give the transaction if it learned
+    /// about it now, without adding it to the mempool or sending anything,
+    /// e.g. to filter transactions before injecting them.
+    pub fn peek_validity(&self, tx: &Transaction) -> Status {
+        self.judge(tx).0
+    }
+
+    /// Returns every response received for the given transaction in the
+    /// order it arrived, including a decision adopted from a peer. Empty
+    /// unless `ConsensusParams::vote_history` is set, `None` if the node does
+    /// not know the transaction.
+    pub fn vote_history(&self, hash: &Hash) -> Option<&[Vote]> {
+        self.mempool.get(hash).map(|state| state.votes.as_slice())
+    }
+
+    /// Returns `(epoch, cnt, status, is_final)` of the given transaction.
+    pub fn summary(&self, hash: &Hash) -> Option<(u32, u32, Status, bool)> {
+        self.mempool
+            .get(hash)
+            .map(|state| (state.epoch, state.cnt, state.status.clone(), state.is_final))
+    }
+
+    /// Returns the hashes of all transactions in the mempool.
+    pub fn hashes(&self) -> Vec<Hash> {
+        self.mempool.keys().cloned().collect()
+    }
+
+    /// Returns every transaction this node finalized along with the status
+    /// it was finalized with, in no particular order. The genesis is left
+    /// out.
+    pub fn decided_transactions(&self) -> impl Iterator<Item = (&Hash, &Status)> {
+        self.mempool
+            .iter()
+            .filter(|(_, state)| state.is_final && !state.tx.is_genesis())
+            .map(|(hash, state)| (hash, &state.status))
+    }
+
+    /// Handle a message from the network. An error is returned if the message
+    /// can not be handled or a message sent in response could not be delivered
+    /// because the network is gone.
+    pub fn handle_message(&mut self, origin: u64, msg: &Message) -> Result {
+        trace!("node={} from={} recv {:?}", self.id, origin, msg);
+
+        match msg {
+            Message::Query(ref msg) => self.handle_query(origin, msg),
+            Message::QueryResponse((_to, ref msg)) => {
+                self.handle_query_responses(origin, slice::from_ref(msg))
+            }
+            Message::Transaction(tx) => self.handle_transactions(slice::from_ref(tx)),
+            Message::QueryBatch(ref batch) => self.handle_query_batch(origin, batch),
+            Message::QueryBatchResponse((_to, ref msgs)) => {
+                self.handle_query_responses(origin, msgs)
+            }
+            Message::Transactions(txs) => self.handle_transactions(txs),
+            Message::Decided((_to, ref hash, ref status)) => {
+                self.handle_decided(origin, hash, status)
+            }
+            Message::MempoolRequest => self.handle_mempool_request(origin),
+            Message::MempoolDigest((_to, ref hashes)) => {
+                self.handle_mempool_digest(origin, hashes)
+            }
+            Message::Pull((_to, ref hashes)) => self.handle_pull(origin, hashes),
+            Message::Pulled((_to, txs)) => self.handle_transactions(txs),
+        }
+    }
+
+    /// Tell a node that just joined which transactions we are still deciding
+    /// on, so it can pull those it misses.
+    fn handle_mempool_request(&self, origin: u64) -> Result {
+        let hashes = self
+            .mempool
+            .iter()
+            .filter(|(_, state)| !state.is_final && !state.is_expired)
+            .map(|(hash, _)| hash.clone())
+            .collect();
+        self.sender.send(self.id, Message::MempoolDigest((origin, hashes)))
+    }
+
+    /// Pull the transactions of the digest we don't know yet.
+    fn handle_mempool_digest(&self, origin: u64, hashes: &[Hash]) -> Result {
+        let missing = hashes
+            .iter()
+            .filter(|hash| !self.mempool.contains_key(hash))
+            .cloned()
+            .collect::<Vec<_>>();
+        if missing.is_empty() {
+            return Ok(());
+        }
+        debug!("node={} pulling {} transactions from {}", self.id, missing.len(), origin);
+        self.sender.send(self.id, Message::Pull((origin, missing)))
+    }
+
+    /// Send the pulled transactions we still have.
+    fn handle_pull(&self, origin: u64, hashes: &[Hash]) -> Result {
+        let txs = hashes
+            .iter()
+            .filter_map(|hash| self.mempool.get(hash))
+            .map(|state| state.tx.clone())
+            .collect::<Vec<_>>();
+        if txs.is_empty() {
+            return Ok(());
+        }
+        self.sender.send(self.id, Message::Pulled((origin, txs)))
+    }
+
+    /// Adopt the decision of a peer that already finalized the transaction.
+    fn handle_decided(&mut self, origin: u64, hash: &Hash, status: &Status) -> Result {
+        let state = match self.mempool.get_mut(hash) {
+            Some(state) if !state.is_final && !state.is_expired => state,
+            _ => return Ok(()),
+        };
+        debug!("node={} tx={} adopting {:?} decided by {}", self.id, hash, status, origin);
+        state.record_vote(origin, status);
+        state.status = status.clone();
+        state.finalize();
+        self.finalized(vec![(hash.clone(), status.clone())])
+    }
+
+    /// Handle the responses of `origin` to one of our queries, and query a
+    /// fresh sample for every transaction whose round ended, in a single
+    /// batch if there are several.
+    fn handle_query_responses(&mut self, origin: u64, msgs: &[QueryResponse]) -> Result {
+        let mut decided = Vec::new();
+        let mut queries = Vec::new();
+        for msg in msgs {
+            match self.handle_query_response(origin, msg, &mut queries) {
+                // Responses can arrive for transactions we no longer or
+                // never tracked, e.g. after resampling. Those are dropped.
+                Err(AvalancheError::UnknownTransaction(hash)) => {
+                    warn!(
+                        "node={} from={} tx={} response for unknown tx",
+                        self.id, origin, hash
+                    );
+                }
+                result => decided.extend(result?),
+            }
+        }
+        // Record what we finalized even if the next queries can't be sent,
+        // e.g. while the network shuts down.
+        let sent = self.send_queries(queries);
+        let recorded = self.finalized(decided);
+        sent.and(recorded)
+    }
+
+    /// Record and report the transactions we just finalized. Returns an
+    /// error if another node finalized one of them differently.
+    fn finalized(&mut self, decided: Vec<(Hash, Status)>) -> Result {
+        let mut violation = None;
+        for (hash, status) in decided {
+            let detected = self.decisions.lock().unwrap().record(self.id, &hash, &status);
+            violation = violation.or(detected);
+            self.observers.emit(|| Event::Finalized {
+                node: self.id,
+                hash: hash.clone(),
+                status: status.clone(),
+            });
+            let state = &self.mempool[&hash];
+            match status {
+                Status::Valid => self.observers.emit(|| Event::Accepted {
+                    node: self.id,
+                    tx: state.tx.clone(),
+                }),
+                Status::Invalid => self.observers.emit(|| Event::Rejected {
+                    node: self.id,
+                    tx: state.tx.clone(),
+                    reason: state.reason,
+                }),
+                Status::Unknown => {}
+            }
+            info!(
+                "node={} tx={} epoch={} decided {:?} in {:?}",
+                self.id,
+                hash,
+                state.epoch,
+                status,
+                state.latency().unwrap()
+            );
+        }
+        match violation {
+            Some(v) => {
+                let err = AvalancheError::SafetyViolation(v);
+                error!("node={} {}", self.id, err);
+                Err(err)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the time it took this node to finalize the given transaction,
+    /// or `None` if it did not finalize it (yet).
+    pub fn latency(&self, hash: &Hash) -> Option<Duration> {
+        self.mempool.get(hash).and_then(|state| state.latency())
+    }
+
+    /// Returns the finalization latency of every transaction this node
+    /// finalized.
+    pub fn latencies(&self) -> HashMap<Hash, Duration> {
+        self.mempool
+            .iter()
+            .filter_map(|(hash, state)| state.latency().map(|l| (hash.clone(), l)))
+            .collect()
+    }
+
+    /// Upon receiving a query, an uncolored node adopts the color in the query,
+    /// responds with that color, and initiates its own query, whereas a colored
+    /// node simply responds with its current color. If the node already prefers
+    /// a conflicting transaction it colors the queried one invalid instead.
+    fn handle_query(&mut self, origin: u64, msg: &QueryMessage) -> Result {
+        let mut queries = Vec::new();
+        let status = self.answer_query(&msg.tx, &msg.status, &mut queries);
+        self.send_queries(queries)?;
+        let hash = msg.tx.id().clone();
+        if self.shares_decision(&hash) {
+            return self.sender.send(self.id, Message::Decided((origin, hash, status)));
+        }
+        self.send_response(origin, hash, status, msg.sampled_stake, msg.round)
+    }
+
+    /// Answer every query of the batch as `handle_query` does, with a single
+    /// batch of responses.
+    fn handle_query_batch(&mut self, origin: u64, batch: &QueryBatch) -> Result {
+        let mut queries = Vec::new();
+        let mut responses = Vec::new();
+        for (tx, status, round) in &batch.queries {
+            let status = self.answer_query(tx, status, &mut queries);
+            let hash = tx.id().clone();
+            if self.shares_decision(&hash) {
+                self.sender.send(self.id, Message::Decided((origin, hash, status)))?;
+                continue;
+            }
+            responses.push(QueryResponse {
+                progress: self.progress(&hash),
+                hash,
+                status,
+                stake: self.stake,
+                sampled_stake: batch.sampled_stake,
+                round: *round,
+            });
+        }
+        self.send_queries(queries)?;
+        if responses.is_empty() {
+            return Ok(());
+        }
+        let msg = Message::QueryBatchResponse((origin, responses));
+        self.sender.send(self.id, msg)
+    }
+
+    /// Drop the responses of node `id` from every open round, after it left
+    /// the network.
+    fn forget_peer(&mut self, id: u64) {
+        for (hash, state) in self.mempool.iter_mut() {
+            if !state.is_final && state.responses.remove(&id).is_some() {
+                debug!("node={} tx={} dropping the response of {} who left", self.id, hash, id);
+            }
+        }
+    }
+
+    /// Returns true if we answer queries for the transaction with our
+    /// decision instead of a response.
+    fn shares_decision(&self, hash: &Hash) -> bool {
+        self.params.share_decisions && self.mempool.get(hash).is_some_and(|state| state.is_final)
+    }
+
+    /// Returns the status to respond to a query for `tx` with color `status`.
+    /// A transaction we did not know yet is added to `queries`, to start
+    /// querying it ourself.
+    fn answer_query(
+        &mut self,
+        tx: &Transaction,
+        status: &Status,
+        queries: &mut Vec<(Transaction, Status)>,
+    ) -> Status {
+        let hash = tx.id().clone();
+        let status = match self.mempool.get_mut(&hash) {
+            // Final decisions don't change, answer without any further work.
+            Some(ref state) if state.is_final => {
+                trace!("node={} tx={} answering query with final status", self.id, hash);
+                state.status.clone()
+            }
+            // We already know the transaction but have no color for it yet.
+            Some(ref mut state)
+                if state.status == Status::Unknown
+                    && self.params.initial_color == ColorPolicy::Adopt =>
+            {
+                state.status = status.clone();
+                state.status.clone()
+            }
+            Some(state) => state.status.clone(),
+            None => {
+                let (status, reason) = match (self.rejection(tx), status) {
+                    (Some(reason), _) => (Status::Invalid, Some(reason)),
+                    // Without a color to adopt we make up our own mind.
+                    (None, Status::Unknown) => self.verify_transaction(tx),
+                    (None, _) if self.params.initial_color == ColorPolicy::Verify => {
+                        self.verify_transaction(tx)
+                    }
+                    (None, status) => (status.clone(), None),
+                };
+                self.add_transaction(hash, tx.clone(), status.clone(), reason);
+                self.queue_query(tx, status.clone(), queries);
+                status
+            }
+        };
+        self.behave(status)
+    }
+
+    /// If k responses are not received within a time bound, the node picks an
+    /// additional sample from the remaining nodes uniformly at random and queries
+    /// them until it collects all responses (see `check_timeouts`).
+    /// Returns every transaction that got finalized by the response.
+    /// TODO: error handling + factor some pieces out of this method!
+    fn handle_query_response(
+        &mut self,
+        origin: u64,
+        msg: &QueryResponse,
+        queries: &mut Vec<(Transaction, Status)>,
+    ) -> Result<Vec<(Hash, Status)>> {
+        let id = self.id;
+        let mut decided = Vec::new();
+        let quorum = {
+            let state = self
+                .mempool
+                .get_mut(&msg.hash)
+                .ok_or_else(|| AvalancheError::UnknownTransaction(msg.hash.clone()))?;
+            // If the state is considered final we dont handle this response anymore.
+            if state.is_final || state.is_expired {
+                return Ok(decided);
+            }
+            // Late responses to the query of an earlier round don't count
+            // towards the quorum of this one.
+            if msg.round != state.round {
+                debug!(
+                    "node={} from={} tx={} dropping response of round {} in round {}",
+                    id, origin, msg.hash, msg.round, state.round
+                );
+                return Ok(decided);
+            }
+            // Duplicate responses, e.g. after resampling, don't count twice.
+            if state.responses.contains_key(&origin) {
+                return Ok(decided);
+            }
+            state
+                .responses
+                .insert(origin, (msg.status.clone(), msg.stake));
+            state.sampled_stake = msg.sampled_stake;
+            state.record_vote(origin, &msg.status);
+            if let Some(progress) = msg.progress {
+                *state.peer_epochs.entry(progress.epoch).or_default() += 1;
+            }
+            self.observers.emit(|| Event::ResponseReceived {
+                node: id,
+                hash: msg.hash.clone(),
+                status: msg.status.clone(),
+            });
+
+            // Wait until every sampled node responded before deciding on the
+            // round. Missing responses are re-sampled by `check_timeouts`.
+            let responded: u64 = state.responses.values().map(|(_, stake)| stake).sum();
+            if state.responses.len() < state.params.k && responded < state.sampled_stake {
+                return Ok(decided);
+            }
+            // Without any stake in the sample every color trivially reaches
+            // the quorum, so the round can't decide anything. The query is
+            // re-sampled once it times out.
+            if responded == 0 {
+                warn!(
+                    "node={} tx={} sample has no stake, skipping round",
+                    id, msg.hash
+                );
+                state.next_round();
+                self.queried.remove(&msg.hash);
+                return Ok(decided);
+            }
+            if state.params.diagnostics {
+                debug!(
+                    "node={} tx={} epoch={} responders per epoch {:?}",
+                    id, msg.hash, state.epoch, state.peer_epochs
+                );
+            }
+
+            let quorum = state.params.quorum(responded);
+            let majority = [Status::Valid, Status::Invalid]
+                .iter()
+                .find(|status| {
+                    let stake: u64 = state
+                        .responses
+                        .values()
+                        .filter(|(s, _)| s == *status)
+                        .map(|(_, stake)| stake)
+                        .sum();
+                    stake > 0 && stake >= quorum
+                })
+                .cloned();
+
+            if let Some(ref status) = majority {
+                if Node::step(id, &self.observers, &msg.hash, state, status) {
+                    state.finalize();
+                    decided.push((msg.hash.clone(), state.status.clone()));
+                }
+            }
+            state.next_round();
+            self.queried.remove(&msg.hash);
+            majority
+        };
+
+        // A quorum accepting the transaction also accepts all of its
+        // ancestors in the DAG.
+        if quorum == Some(Status::Valid) {
+            for hash in self.ancestors(&msg.hash) {
+                let state = self.mempool.get_mut(&hash).unwrap();
+                let valid = Status::Valid;
+                if !state.is_final && Node::step(id, &self.observers, &hash, state, &valid) {
+                    state.finalize();
+                    decided.push((hash, state.status.clone()));
+                }
+            }
+        }
+
+        // Query a fresh sample for the next round, unless we ran out of time.
+        let state = self.mempool.get_mut(&msg.hash).unwrap();
+        if !state.is_final && !Node::expire(id, &self.observers, &msg.hash, state) {
+            let (tx, status) = (state.tx.clone(), state.status.clone());
+            self.queue_query(&tx, status, queries);
+        }
+        Ok(decided)
+    }
+
+    /// Step the state of transaction `hash` on a quorum for `status`,
+    /// reporting color flips and new epochs to the observers. Returns true
+    /// once the transaction is final.
+    fn step(
+        id: u64,
+        observers: &Observers,
+        hash: &Hash,
+        state: &mut TxState,
+        status: &Status,
+    ) -> bool {
+        let (color, epoch) = (state.status.clone(), state.epoch);
+        let is_final = state.step(status);
+        if state.status != color {
+            observers.emit(|| Event::ColorFlipped {
+                node: id,
+                hash: hash.clone(),
+                status: state.status.clone(),
+            });
+        }
+        if state.epoch != epoch {
+            observers.emit(|| Event::EpochAdvanced {
+                node: id,
+                hash: hash.clone(),
+                status: state.status.clone(),
+                epoch: state.epoch,
+            });
+        }
+        is_final
+    }
+
+    /// Expire the transaction `hash` if it outlived the ttl undecided,
+    /// reporting it to the observers. Returns true if it is expired.
+    fn expire(id: u64, observers: &Observers, hash: &Hash, state: &mut TxState) -> bool {
+        if state.expire() {
+            warn!("node={} tx={} expired undecided after {} epochs", id, hash, state.epoch);
+            observers.emit(|| Event::Expired {
+                node: id,
+                hash: hash.clone(),
+                status: state.status.clone(),
+            });
+        }
+        state.is_expired
+    }
+
+    /// Returns all known ancestors of the given transaction.
+    fn ancestors(&self, hash: &Hash) -> Vec<Hash> {
+        let mut seen = HashSet::new();
+        let mut ancestors = Vec::new();
+        let mut todo = vec![hash.clone()];
+        while let Some(hash) = todo.pop() {
+            let state = match self.mempool.get(&hash) {
+                Some(state) => state,
+                None => continue,
+            };
+            for parent in &state.tx.parents {
+                if self.mempool.contains_key(parent) && seen.insert(parent.clone()) {
+                    ancestors.push(parent.clone());
+                    todo.push(parent.clone());
+                }
+            }
+        }
+        ancestors
+    }
+
+    fn handle_transactions(&mut self, txs: &[Transaction]) -> Result {
+        let mut queries = Vec::new();
+        for tx in txs {
+            // We might have learned about the transaction from a query already.
+            if self.mempool.contains_key(tx.id()) {
+                continue;
+            }
+            // Verify transaction ourself, a transaction conflicting with one we
+            // already prefer or building on one we reject is never valid.
+            let (status, reason) = self.judge(tx);
+            let status = self.behave(status);
+            let reason = reason.filter(|_| status == Status::Invalid);
+
+            // Add the tx to our mempool.
+            self.add_transaction(tx.id().clone(), tx.clone(), status.clone(), reason);
+            self.queue_query(tx, status, &mut queries);
+        }
+        self.send_queries(queries)
+    }
+
+    /// Insert a new transaction in the mempool and its conflict set.
+    fn add_transaction(
+        &mut self,
+        hash: Hash,
+        tx: Transaction,
+        status: Status,
+        reason: Option<InvalidReason>,
+    ) {
+        self.evict();
+        self.conflict_sets
+            .entry(tx.conflict_id)
+            .or_default()
+            .push(hash.clone());
+        let mut state = TxState::new(tx, status, self.params).with_clock(self.clock.clone());
+        state.reason = reason;
+        self.mempool.insert(hash, state);
+    }
+
+    /// Make room for a new transaction if the mempool is at capacity. The
+    /// transaction finalized the longest ago is evicted first, followed by
+    /// expired ones. Without those the one queried the longest ago is dropped,
+    /// together with its consensus state. Within each of these the lowest
+    /// priority goes first. Responses for evicted transactions are ignored
+    /// and a later query for them starts over, see `queue_query`. The
+    /// genesis is never evicted and does not count towards the capacity.
+    fn evict(&mut self) {
+        let capacity = match self.params.mempool_capacity {
+            Some(capacity) => capacity.max(1),
+            None => return,
+        };
+        self.forget_lost_queries();
+        while self.mempool.len() > capacity {
+            let hash = self
+                .mempool
+                .iter()
+                .filter(|(_, state)| !state.tx.is_genesis())
+                .min_by_key(|(_, state)| {
+                    let active = !state.is_final && !state.is_expired;
+                    let at = state.finalized_at.unwrap_or(state.queried_at);
+                    (active, !state.is_final, state.tx.priority, at)
+                })
+                .map(|(hash, _)| hash.clone());
+            let hash = match hash {
+                Some(hash) => hash,
+                None => return,
+            };
+            let state = self.mempool.remove(&hash).unwrap();
+            debug!(
+                "node={} tx={} evicted, final={} priority={}",
+                self.id, hash, state.is_final, state.tx.priority
+            );
+            if let Some(set) = self.conflict_sets.get_mut(&state.tx.conflict_id) {
+                set.retain(|other| *other != hash);
+                if set.is_empty() {
+                    self.conflict_sets.remove(&state.tx.conflict_id);
+                }
+            }
+        }
+    }
+
+    /// Returns why a new transaction can not be preferred, if we already
+    /// prefer a conflicting transaction or reject one of its parents.
+    fn rejection(&self, tx: &Transaction) -> Option<InvalidReason> {
+        if self.preferred(tx.conflict_id).is_some() {
+            return Some(InvalidReason::Conflict);
+        }
+        let rejected_parent = tx.parents.iter().any(|parent| {
+            self.mempool
+                .get(parent)
+                .is_some_and(|state| state.status == Status::Invalid)
+        });
+        match rejected_parent {
+            true => Some(InvalidReason::InvalidParent),
+            false => None,
+        }
+    }
+
+    /// Returns the color we give a transaction we see for the first time,
+    /// with the reason if it is invalid.
+    fn judge(&self, tx: &Transaction) -> (Status, Option<InvalidReason>) {
+        match self.rejection(tx) {
+            Some(reason) => (Status::Invalid, Some(reason)),
+            None => self.verify_transaction(tx),
+        }
+    }
+
+    /// Returns the transaction we currently prefer in the given conflict set,
+    /// which is the first seen transaction we consider valid.
+    fn preferred(&self, conflict_id: ConflictId) -> Option<&Hash> {
+        let mempool = &self.mempool;
+        self.conflict_sets.get(&conflict_id).and_then(|set| {
+            set.iter()
+                .find(|hash| mempool.get(*hash).is_some_and(|s| s.status == Status::Valid))
+        })
+    }
+
+    /// Re-sample the missing responses of every query that is still waiting
+    /// for responses after `query_timeout`. Nodes that already responded in the
+    /// current epoch are excluded from the new sample.
+    fn check_timeouts(&mut self) -> Result {
+        let id = self.id;
+        self.forget_lost_queries();
+        for (hash, state) in self.mempool.iter_mut() {
+            if state.is_final
+                || Node::expire(id, &self.observers, hash, state)
+                || state.responses.len() >= state.params.k
+                || state.elapsed(state.queried_at) < self.query_timeout
+            {
+                continue;
+            }
+            state.queried_at = state.clock.now();
+            self.queried.insert(hash.clone(), (state.queried_at, state.round));
+            let msg = Message::Query(QueryMessage {
+                tx: state.tx.clone(),
+                status: state.status.clone(),
+                samples: state.params.k - state.responses.len(),
+                exclude: state.responses.keys().cloned().collect(),
+                sampled_stake: 0,
+                round: state.round,
+            });
+            self.sender.send(self.id, msg)?;
+        }
+        Ok(())
+    }
+
+    /// Add a query for a new round on `tx` to `queries`, unless the query of
+    /// the current round is still in flight. That happens when a transaction
+    /// is evicted and seen again before the responses to our query arrived.
+    /// The transaction then continues that round, so the responses still in
+    /// flight count for it. A query older than the query timeout is
+    /// considered lost.
+    fn queue_query(
+        &mut self,
+        tx: &Transaction,
+        status: Status,
+        queries: &mut Vec<(Transaction, Status)>,
+    ) {
+        let now = self.clock.now();
+        if let Some(&(at, round)) = self.queried.get(tx.id()) {
+            if now.saturating_duration_since(at) < self.query_timeout {
+                trace!("node={} tx={} query of round {} in flight", self.id, tx.id(), round);
+                if let Some(state) = self.mempool.get_mut(tx.id()) {
+                    state.round = round;
+                    state.queried_at = at;
+                }
+                return;
+            }
+        }
+        self.round = self.round.wrapping_add(1);
+        self.queried.insert(tx.id().clone(), (now, self.round));
+        if let Some(state) = self.mempool.get_mut(tx.id()) {
+            state.round = self.round;
+        }
+        queries.push((tx.clone(), status));
+    }
+
+    /// Forget the queries older than the query timeout, whose transactions
+    /// start a new round when they are queried again. Those are left behind
+    /// by evicted transactions that are never seen again, so this runs on
+    /// every eviction, whichever executor delivers the messages.
+    fn forget_lost_queries(&mut self) {
+        let now = self.clock.now();
+        let timeout = self.query_timeout;
+        self.queried.retain(|_, (at, _)| now.saturating_duration_since(*at) < timeout);
+    }
+
+    /// Round of the latest query on the transaction.
+    fn round_of(&self, tx: &Transaction) -> u32 {
+        self.mempool.get(tx.id()).map_or(0, |state| state.round)
+    }
+
+    fn send_query(&self, tx: Transaction, status: Status) -> Result {
+        debug!("node={} tx={} query {:?}", self.id, tx.id(), status);
+        self.observers.emit(|| Event::QuerySent {
+            node: self.id,
+            hash: tx.id().clone(),
+            status: status.clone(),
+        });
+        let msg = Message::Query(QueryMessage {
+            round: self.round_of(&tx),
+            tx,
+            status,
+            samples: self.params.k,
+            exclude: Vec::new(),
+            sampled_stake: 0,
+        });
+        self.sender.send(self.id, msg)
+    }
+
+    /// Query a sample for all of the transactions, with a single query or a
+    /// batch if there are several.
+    fn send_queries(&self, mut queries: Vec<(Transaction, Status)>) -> Result {
+        if queries.len() <= 1 {
+            return match queries.pop() {
+                Some((tx, status)) => self.send_query(tx, status),
+                None => Ok(()),
+            };
+        }
+        for (tx, status) in &queries {
+            debug!("node={} tx={} query {:?}", self.id, tx.id(), status);
+            self.observers.emit(|| Event::QuerySent {
+                node: self.id,
+                hash: tx.id().clone(),
+                status: status.clone(),
+            });
+        }
+        let queries = queries
+            .into_iter()
+            .map(|(tx, status)| {
+                let round = self.round_of(&tx);
+                (tx, status, round)
+            })
+            .collect();
+        let msg = Message::QueryBatch(QueryBatch::new(queries, self.params.k));
+        self.sender.send(self.id, msg)
+    }
+
+    fn send_response(
+        &self,
+        to: u64,
+        hash: Hash,
+        status: Status,
+        sampled_stake: u64,
+        round: u32,
+    ) -> Result {
+        let progress = self.progress(&hash);
+        let msg = Message::QueryResponse((
+            to,
+            QueryResponse {
+                hash,
+                status,
+                stake: self.stake,
+                sampled_stake,
+                progress,
+                round,
+            },
+        ));
+        self.sender.send(self.id, msg)
+    }
+
+    /// Our progress on the transaction to send along with a response, if
+    /// diagnostics are enabled.
+    fn progress(&self, hash: &Hash) -> Option<Progress> {
+        if !self.params.diagnostics {
+            return None;
+        }
+        self.mempool.get(hash).map(|state| Progress {
+            epoch: state.epoch,
+            cnt: state.cnt,
+        })
+    }
+
+    /// Returns the status this node reports given the honest status, which
+    /// differs from the honest one for byzantine nodes.
+    fn behave(&mut self, honest: Status) -> Status {
+        match self.behavior {
+            Behavior::Honest => honest,
+            Behavior::AlwaysValid => Status::Valid,
+            Behavior::AlwaysInvalid => Status::Invalid,
+            Behavior::Random if self.rng.gen() => Status::Valid,
+            Behavior::Random => Status::Invalid,
+            Behavior::Flip => honest.flip(),
+        }
+    }
+
+    fn verify_transaction(&self, tx: &Transaction) -> (Status, Option<InvalidReason>) {
+        // A signed transaction whose signature does not match was tampered
+        // with.
+        if tx.signature.is_some() && !tx.verify_signature() {
+            return (Status::Invalid, Some(InvalidReason::BadSignature));
+        }
+        self.validator.verify(tx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A seeded network of `n` nodes measuring time on `clock`.
+    fn network(n: u64, seed: u64, clock: &MockClock) -> Network {
+        Network::builder()
+            .nodes(n)
+            .seed(seed)
+            .clock(Arc::new(clock.clone()))
+            .record()
+            .build()
+    }
+
+    /// Let every query time out and every node re-sample its stalled rounds,
+    /// then deliver everything that sends.
+    fn resample(net: &Network, clock: &MockClock) {
+        clock.advance(QUERY_TIMEOUT);
+        for node in net.nodes().values() {
+            lock_node(node).check_timeouts().unwrap();
+        }
+        net.run_until_quiescent().unwrap();
+    }
+
+    fn state(mode: ConsensusMode, status: Status) -> TxState {
+        let params = ConsensusParams {
+            mode,
+            ..ConsensusParams::default()
+        };
+        TxState::new(Transaction::random_from(&mut seeded_rng(1)), status, params)
+    }
+
+    #[test]
+    fn query_with_a_dead_responder_is_resampled() {
+        let clock = MockClock::new();
+        let net = network(10, 1, &clock);
+        let mut tx = Transaction::random_from(&mut seeded_rng(1));
+        tx.data = 0;
+        let hash = tx.id().clone();
+        net.inject_to(0, tx).unwrap();
+        // Deliver the query of node 0 and kill one of the sampled nodes
+        // before its response arrives.
+        assert!(net.step().unwrap());
+        let sampled = net.message_log().unwrap().entries.last().unwrap().to;
+        net.remove_node(sampled).unwrap();
+        net.run_until_quiescent().unwrap();
+        let confidence = net.query_confidence(0, &hash).unwrap().unwrap();
+        assert!(!confidence.is_final);
+
+        for _ in 0..20 {
+            if net.finalized_by(&hash) == net.node_ids().len() {
+                break;
+            }
+            resample(&net, &clock);
+        }
+        assert_eq!(net.finalized_by(&hash), net.node_ids().len());
+        assert_eq!(net.network_decision(&hash), Some(Status::Valid));
+    }
+
+    #[test]
+    fn churn_routes_queries_around_removed_nodes_to_joined_ones() {
+        let clock = MockClock::new();
+        let net = network(10, 2, &clock);
+        let mut tx = Transaction::random_from(&mut seeded_rng(2));
+        tx.data = 0;
+        let hash = tx.id().clone();
+        net.inject_to(0, tx).unwrap();
+        assert!(net.step().unwrap());
+        // Nodes 4 and 7 leave while the query of node 0 waits for their
+        // responses.
+        net.remove_node(4).unwrap();
+        net.remove_node(7).unwrap();
+        let joined = net.add_node();
+        assert_eq!(joined, 10);
+        let removed_at = net.message_log().unwrap().len();
+
+        for _ in 0..20 {
+            if net.finalized_by(&hash) == net.node_ids().len() {
+                break;
+            }
+            resample(&net, &clock);
+        }
+        assert_eq!(net.finalized_by(&hash), net.node_ids().len());
+        let log = net.message_log().unwrap();
+        let after = &log.entries[removed_at..];
+        assert!(after.iter().all(|entry| entry.to != 4 && entry.to != 7));
+        assert!(after.iter().any(|entry| entry.to == joined));
+        let confidence = net.query_confidence(joined, &hash).unwrap().unwrap();
+        assert_eq!((confidence.status, confidence.is_final), (Status::Valid, true));
+    }
+
+    #[test]
+    fn transaction_without_a_possible_quorum_expires() {
+        let clock = MockClock::new();
+        let params = ConsensusParams {
+            ttl: Some(QUERY_TIMEOUT * 3),
+            ..ConsensusParams::default()
+        };
+        let net = Network::builder()
+            .nodes(10)
+            .seed(3)
+            .params(params)
+            .clock(Arc::new(clock.clone()))
+            .build();
+        // Node 0 can't reach anybody, so its queries never get a response.
+        net.partition(&[0], &(1..10).collect::<Vec<_>>());
+        let tx = Transaction::random_from(&mut seeded_rng(3));
+        let hash = tx.id().clone();
+        net.inject_to(0, tx).unwrap();
+        net.run_until_quiescent().unwrap();
+
+        let held = || net.partition.lock().unwrap().held.len();
+        for _ in 0..2 {
+            let before = held();
+            resample(&net, &clock);
+            assert!(held() > before, "stalled query not re-sent");
+        }
+        resample(&net, &clock);
+        let confidence = net.query_confidence(0, &hash).unwrap().unwrap();
+        assert!(confidence.is_expired && !confidence.is_final);
+        // An expired transaction is no longer re-queried.
+        let before = held();
+        resample(&net, &clock);
+        assert_eq!(held(), before);
+    }
+
+    #[test]
+    fn query_batch_is_answered_in_one_response() {
+        let (sender, receiver) = channel();
+        let mut node = Node::new_with_mempool(0, sender, ConsensusParams::default(), Vec::new());
+        let txs: Vec<_> = (0..10)
+            .map(|data| {
+                let mut tx = Transaction::random_from(&mut seeded_rng(data as u64));
+                tx.data = data;
+                tx
+            })
+            .collect();
+        let queries = txs
+            .iter()
+            .enumerate()
+            .map(|(round, tx)| (tx.clone(), Status::Unknown, round as u32))
+            .collect();
+        let batch = Message::QueryBatch(QueryBatch::new(queries, 4));
+        node.handle_message(1, &batch).unwrap();
+
+        let responses: Vec<_> = receiver
+            .try_iter()
+            .filter_map(|envelope| match envelope.msg {
+                Message::QueryBatchResponse((to, resps)) => Some((to, resps)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(responses.len(), 1);
+        let (to, ref resps) = responses[0];
+        assert_eq!((to, resps.len()), (1, txs.len()));
+        for (round, (tx, resp)) in txs.iter().zip(resps).enumerate() {
+            let expected = match tx.data < VALID_DATA_LIMIT {
+                true => Status::Valid,
+                false => Status::Invalid,
+            };
+            assert_eq!(&resp.hash, tx.id());
+            assert_eq!((&resp.status, resp.round), (&expected, round as u32));
+        }
+    }
+
+    #[test]
+    fn query_times_out_when_the_clock_advances() {
+        let clock = MockClock::new();
+        let tx = Transaction::random_from(&mut seeded_rng(5));
+        let params = ConsensusParams::default();
+        let state = TxState::new(tx, Status::Valid, params).with_clock(Arc::new(clock.clone()));
+        let (sender, receiver) = channel();
+        let mut node = Node::new_with_mempool(0, sender, params, vec![state]);
+        node.clock = Arc::new(clock.clone());
+
+        clock.advance(QUERY_TIMEOUT - Duration::from_millis(1));
+        node.check_timeouts().unwrap();
+        assert!(receiver.try_recv().is_err());
+        clock.advance(Duration::from_millis(1));
+        node.check_timeouts().unwrap();
+        match receiver.try_recv().unwrap().msg {
+            Message::Query(ref query) => assert_eq!(query.samples, params.k),
+            ref msg => panic!("expected a query, got {:?}", msg),
+        }
+        // The re-sent query starts a new timeout.
+        node.check_timeouts().unwrap();
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn a_panicking_node_fails_alone() {
+        let clock = MockClock::new();
+        // Let every node verify the transaction itself.
+        let params = ConsensusParams {
+            initial_color: ColorPolicy::Verify,
+            ..ConsensusParams::default()
+        };
+        let net = Network::builder()
+            .nodes(10)
+            .seed(6)
+            .params(params)
+            .clock(Arc::new(clock.clone()))
+            .build();
+        let broken = |_: &Transaction| -> Status { panic!("broken validator") };
+        net.set_validator(3, Arc::new(broken)).unwrap();
+        let mut tx = Transaction::random_from(&mut seeded_rng(6));
+        tx.data = 0;
+        let hash = tx.id().clone();
+        net.inject_to(0, tx).unwrap();
+        // The executor reports the failure, the other nodes carry on.
+        assert_eq!(net.run_until_quiescent(), Err(AvalancheError::Poisoned(3)));
+        net.run_until_quiescent().unwrap();
+        assert_eq!(net.failed_nodes(), vec![3]);
+
+        // Rounds that sampled the failed node are re-sampled without it.
+        for _ in 0..20 {
+            if net.finalized_by(&hash) == 9 {
+                break;
+            }
+            resample(&net, &clock);
+        }
+        assert_eq!(net.finalized_by(&hash), 9);
+        assert_eq!(net.network_decision(&hash), Some(Status::Valid));
+    }
+
+    /// Samples `origin`, every excluded node and every candidate twice.
+    struct Careless;
+
+    impl SamplingStrategy for Careless {
+        fn sample(
+            &self,
+            _: &mut dyn RngCore,
+            origin: u64,
+            candidates: &[Candidate],
+            _: usize,
+            exclude: &[u64],
+        ) -> Vec<u64> {
+            let ids = candidates.iter().map(|candidate| candidate.id);
+            let mut sampled = vec![origin];
+            sampled.extend(exclude);
+            sampled.extend(ids.clone().chain(ids));
+            sampled
+        }
+    }
+
+    #[test]
+    fn nodes_never_sample_themselves() {
+        let mut rng = seeded_rng(4);
+        for n in 1..6 {
+            let net = Network::new_seeded(n, 4);
+            let nodes = net.nodes();
+            let topology = Topology::full_mesh();
+            for strategy in &[&Uniform as &dyn SamplingStrategy, &StakeWeighted, &Careless] {
+                let sampler = Sampler {
+                    topology: &topology,
+                    strategy: *strategy,
+                };
+                for origin in 0..n {
+                    let exclude = [(origin + 1) % n];
+                    let sampled = sampler.sample(&mut rng, &nodes, origin, &exclude, 4);
+                    // Small enough that every other peer is sampled.
+                    let expected: Vec<_> =
+                        (0..n).filter(|id| *id != origin && *id != exclude[0]).collect();
+                    let mut sorted = sampled.clone();
+                    sorted.sort();
+                    assert_eq!(sorted, expected, "{} nodes, origin {}", n, origin);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn fair_queue_takes_turns_between_senders() {
+        let mut queue = FairQueue::default();
+        let mut seq = 0;
+        let mut push = |queue: &mut FairQueue, origin| {
+            seq += 1;
+            queue.push(Envelope {
+                seq,
+                origin,
+                msg: Message::MempoolRequest,
+            });
+        };
+        // Node 0 floods the queue before the others send anything.
+        for _ in 0..10 {
+            push(&mut queue, 0);
+        }
+        push(&mut queue, 1);
+        push(&mut queue, 2);
+        let order: Vec<_> = ::std::iter::from_fn(|| queue.pop())
+            .map(|envelope| (envelope.origin, envelope.seq))
+            .collect();
+        assert_eq!(&order[..4], &[(0, 1), (1, 11), (2, 12), (0, 2)]);
+        // The noisy node still gets all of its messages through, in order.
+        let noisy: Vec<_> = order.iter().filter(|(origin, _)| *origin == 0).collect();
+        assert_eq!(noisy.len(), 10);
+        assert!(noisy.windows(2).all(|pair| pair[0].1 < pair[1].1));
+    }
+
+    #[test]
+    fn hashes_travel_as_raw_bytes() {
+        let hashes: Vec<_> = (0..3)
+            .map(|seed| Transaction::random_from(&mut seeded_rng(seed)).id().clone())
+            .collect();
+        let mut buf = Vec::new();
+        write_message(&mut buf, &Message::MempoolDigest((3, hashes.clone()))).unwrap();
+        // Tag, recipient and count, then 32 bytes per hash instead of 64 hex
+        // characters.
+        assert_eq!(buf.len(), 1 + 8 + 4 + 32 * hashes.len());
+        assert_eq!(&buf[13..45], hashes[0].as_bytes());
+        match read_message(&mut &buf[..]).unwrap() {
+            Message::MempoolDigest((3, ref read)) => assert_eq!(read, &hashes),
+            msg => panic!("expected a mempool digest, got {:?}", msg),
+        }
+    }
+
+    #[test]
+    fn snowflake_flips_on_a_single_quorum_for_the_other_color() {
+        let mut state = state(ConsensusMode::Snowflake, Status::Valid);
+        state.step(&Status::Valid);
+        state.step(&Status::Valid);
+        assert_eq!(state.cnt(), 2);
+        state.step(&Status::Invalid);
+        assert_eq!((state.status(), state.cnt()), (&Status::Invalid, 0));
+    }
+
+    #[test]
+    fn snowball_keeps_its_color_until_the_other_one_gathered_more_quorums() {
+        let mut state = state(ConsensusMode::Snowball, Status::Valid);
+        state.step(&Status::Valid);
+        state.step(&Status::Valid);
+        state.step(&Status::Invalid);
+        assert_eq!((state.status(), state.cnt()), (&Status::Valid, 2));
+        state.step(&Status::Invalid);
+        state.step(&Status::Invalid);
+        assert_eq!(state.status(), &Status::Invalid);
+    }
+
+    #[test]
+    fn snowball_resets_the_counter_only_when_its_preference_flips() {
+        use self::Status::{Invalid, Valid};
+        let mut state = state(ConsensusMode::Snowball, Invalid);
+        // The tie after the fourth quorum keeps the current color.
+        assert_eq!(state.tie_break(), Invalid);
+        let quorums = [Invalid, Invalid, Valid, Valid, Valid, Valid, Invalid, Valid];
+        let trajectory: Vec<_> = quorums
+            .iter()
+            .map(|status| {
+                state.step(status);
+                (state.status().clone(), state.cnt())
+            })
+            .collect();
+        assert_eq!(
+            trajectory,
+            vec![
+                (Invalid, 1),
+                (Invalid, 2),
+                (Invalid, 2),
+                (Invalid, 2),
+                (Valid, 0),
+                (Valid, 1),
+                (Valid, 1),
+                (Valid, 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn uncolored_state_adopts_either_color_alike() {
+        assert_eq!(Status::Unknown.flip(), Status::Unknown);
+        for &mode in &[ConsensusMode::Snowflake, ConsensusMode::Snowball] {
+            for color in &[Status::Valid, Status::Invalid] {
+                let mut state = state(mode, Status::Unknown);
+                state.step(color);
+                assert_eq!((state.status(), state.cnt()), (color, 0), "{:?}", mode);
+                state.step(color);
+                assert_eq!((state.status(), state.cnt()), (color, 1), "{:?}", mode);
+            }
+        }
+    }
+
+    #[test]
+    fn fresh_valid_transaction_finalizes_in_minimal_epochs() {
+        let params = ConsensusParams::default();
+        let rounds = |mut state: TxState, color: &Status| {
+            let mut rounds = 1;
+            while !state.step(color) {
+                rounds += 1;
+                assert!(rounds <= 20, "not final after {} rounds", rounds);
+            }
+            assert_eq!((state.status(), state.epoch()), (color, params.beta));
+            rounds
+        };
+        for &mode in &[ConsensusMode::Snowflake, ConsensusMode::Snowball] {
+            // Adopting the color takes a single quorum on top of those of a
+            // colored state, whichever the color is.
+            let colored = rounds(state(mode, Status::Valid), &Status::Valid);
+            assert_eq!(colored, 3 + params.beta, "{:?}", mode);
+            let fresh = rounds(state(mode, Status::Unknown), &Status::Valid);
+            assert_eq!(fresh, colored + 1, "{:?}", mode);
+            let invalid = rounds(state(mode, Status::Unknown), &Status::Invalid);
+            assert_eq!(invalid, fresh, "{:?}", mode);
+        }
+    }
+
+    #[test]
+    fn finalizing_takes_exactly_beta_advances() {
+        for &beta in &[1, 2, 4, 7] {
+            let params = ConsensusParams {
+                beta,
+                ..ConsensusParams::default()
+            };
+            let tx = Transaction::random_from(&mut seeded_rng(1));
+            let mut state = TxState::new(tx, Status::Valid, params);
+            let mut advances = 0;
+            loop {
+                let epoch = state.epoch();
+                let is_final = state.step(&Status::Valid);
+                if state.epoch() != epoch {
+                    assert_eq!(state.epoch(), epoch + 1);
+                    assert!(state.cnt() > params.conviction_treshold());
+                    advances += 1;
+                }
+                if is_final {
+                    break;
+                }
+                assert!(advances < beta, "beta {}: not final after {}", beta, advances);
+            }
+            assert_eq!((advances, state.epoch()), (beta, beta));
+        }
+    }
+
+    #[test]
+    fn one_quorum_short_of_beta_epochs_is_not_final() {
+        let params = ConsensusParams::default();
+        // More than the treshold for the first epoch, one more per epoch after.
+        let needed = params.conviction_treshold() + params.beta;
+        for &mode in &[ConsensusMode::Snowflake, ConsensusMode::Snowball] {
+            let mut state = state(mode, Status::Valid);
+            for _ in 0..needed - 1 {
+                assert!(!state.step(&Status::Valid), "{:?}", mode);
+            }
+            assert_eq!(state.epoch(), params.beta - 1, "{:?}", mode);
+            assert!(state.step(&Status::Valid), "{:?}", mode);
+        }
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "accepted epoch 1 after 1 quorums")]
+    fn epoch_without_a_conviction_behind_it_is_caught() {
+        let mut state = state(ConsensusMode::Snowball, Status::Valid);
+        // A counter that skipped the quorums convincing the node.
+        state.cnt = state.params.conviction_treshold() + 1;
+        state.step(&Status::Valid);
+    }
+
+    #[test]
+    fn counters_at_their_cap_saturate() {
+        let max = u32::MAX;
+        for &mode in &[ConsensusMode::Snowflake, ConsensusMode::Snowball] {
+            let capped = || {
+                state(mode, Status::Valid)
+                    .with_epoch(max)
+                    .with_cnt(max)
+                    .with_confidence(max, max)
+            };
+            // A quorum for the current color accepts yet another epoch.
+            let mut state = capped();
+            assert!(state.step(&Status::Valid), "{:?}", mode);
+            assert_eq!((state.epoch(), state.cnt()), (max, max), "{:?}", mode);
+            assert_eq!(state.quorums, max);
+
+            let mut state = capped();
+            state.step(&Status::Invalid);
+            assert_eq!(state.epoch(), max, "{:?}", mode);
+            if mode == ConsensusMode::Snowball {
+                assert_eq!((state.cnt_valid(), state.cnt_invalid()), (max, max));
+            }
+        }
+    }
+
+    #[test]
+    fn alternating_quorums_flip_snowflake_but_not_snowball() {
+        let alternating = [Status::Invalid, Status::Valid];
+        let mut snowball = state(ConsensusMode::Snowball, Status::Invalid);
+        // Every quorum for Valid ties the counts, which keeps Invalid.
+        assert_eq!(snowball.tie_break(), Status::Invalid);
+        let mut steps = 0;
+        while !snowball.step(&alternating[steps % 2]) {
+            assert_eq!(snowball.status(), &Status::Invalid);
+            steps += 1;
+            assert!(steps < 100, "snowball did not converge");
+        }
+        assert_eq!(snowball.status(), &Status::Invalid);
+
+        let mut snowflake = state(ConsensusMode::Snowflake, Status::Valid);
+        for status in alternating.iter().cycle().take(100) {
+            assert!(!snowflake.step(status));
+            assert_eq!((snowflake.status(), snowflake.cnt()), (status, 0));
+        }
+    }
+
+    #[test]
+    fn nodes_break_a_tie_the_same_way() {
+        let params = ConsensusParams::default();
+        let mut valid = 0;
+        for seed in 0..8 {
+            let tx = Transaction::random_from(&mut seeded_rng(seed));
+            // Two nodes preferring different colors each see one quorum for
+            // their own color and one for the other.
+            let colors = [Status::Valid, Status::Invalid];
+            let decided: Vec<_> = colors
+                .iter()
+                .map(|color| {
+                    let mut state = TxState::new(tx.clone(), color.clone(), params);
+                    state.step(color);
+                    state.step(&color.flip());
+                    assert_eq!((state.cnt_valid(), state.cnt_invalid()), (1, 1));
+                    state.status().clone()
+                })
+                .collect();
+            let tie_break = TxState::new(tx, Status::Unknown, params).tie_break();
+            assert_eq!(decided, vec![tie_break.clone(), tie_break.clone()], "seed {}", seed);
+            if tie_break == Status::Valid {
+                valid += 1;
+            }
+        }
+        // Neither color is favored over all transactions.
+        assert!(valid > 0 && valid < 8);
+    }
+}
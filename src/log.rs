@@ -0,0 +1,181 @@
+//! Minimal leveled logging to stderr, configured through the `RUST_LOG`
+//! environment variable using the same syntax as `env_logger`, e.g.
+//! `RUST_LOG=avalanche=debug` or `RUST_LOG=off`. Without `RUST_LOG` info and
+//! more severe messages are logged.
+
+use std::env;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    fn parse(s: &str) -> Option<Level> {
+        match s.trim().to_lowercase().as_str() {
+            "off" => Some(Level::Off),
+            "error" => Some(Level::Error),
+            "warn" => Some(Level::Warn),
+            "info" => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            "trace" => Some(Level::Trace),
+            _ => None,
+        }
+    }
+
+    fn from_usize(n: usize) -> Level {
+        match n {
+            0 => Level::Off,
+            1 => Level::Error,
+            2 => Level::Warn,
+            3 => Level::Info,
+            4 => Level::Debug,
+            _ => Level::Trace,
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Level::Off => "OFF",
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        };
+        f.pad(s)
+    }
+}
+
+const UNINITIALIZED: usize = usize::MAX;
+
+static LEVEL: AtomicUsize = AtomicUsize::new(UNINITIALIZED);
+
+/// Parse the level for this crate out of a `RUST_LOG` value. Directives are
+/// comma separated and either a bare level or `target=level`, only the
+/// `avalanche` target is considered.
+fn parse_filter(filter: &str) -> Option<Level> {
+    let mut level = None;
+    for directive in filter.split(',') {
+        let mut parts = directive.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(lvl), None) => level = Level::parse(lvl).or(level),
+            (Some(target), Some(lvl)) if target.trim().starts_with("avalanche") => {
+                level = Level::parse(lvl).or(level)
+            }
+            _ => {}
+        }
+    }
+    level
+}
+
+/// Configure the log level from `RUST_LOG`. Called lazily on the first log
+/// statement, calling it explicitly re-reads the environment.
+pub fn init() {
+    let level = env::var("RUST_LOG")
+        .ok()
+        .and_then(|filter| parse_filter(&filter))
+        .unwrap_or(Level::Info);
+    set_level(level);
+}
+
+/// Override the log level regardless of `RUST_LOG`.
+pub fn set_level(level: Level) {
+    LEVEL.store(level as usize, Ordering::Relaxed);
+}
+
+/// The level up to which messages are logged.
+pub fn level() -> Level {
+    if LEVEL.load(Ordering::Relaxed) == UNINITIALIZED {
+        init();
+    }
+    Level::from_usize(LEVEL.load(Ordering::Relaxed))
+}
+
+#[doc(hidden)]
+pub fn log(level: Level, args: fmt::Arguments) {
+    eprintln!("{:5} avalanche: {}", level, args);
+}
+
+/// Log a message at the given level, e.g.
+/// `av_log!(Level::Info, "node={} joined", id)`. The macros are prefixed so
+/// they don't clash with those of the `log` crate.
+#[macro_export]
+macro_rules! av_log {
+    ($lvl:expr, $($arg:tt)+) => {
+        if $lvl != $crate::log::Level::Off && $lvl <= $crate::log::level() {
+            $crate::log::log($lvl, format_args!($($arg)+));
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! av_error {
+    ($($arg:tt)+) => { $crate::av_log!($crate::log::Level::Error, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! av_warn {
+    ($($arg:tt)+) => { $crate::av_log!($crate::log::Level::Warn, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! av_info {
+    ($($arg:tt)+) => { $crate::av_log!($crate::log::Level::Info, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! av_debug {
+    ($($arg:tt)+) => { $crate::av_log!($crate::log::Level::Debug, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! av_trace {
+    ($($arg:tt)+) => { $crate::av_log!($crate::log::Level::Trace, $($arg)+) };
+}
+
+// Unprefixed shorthands for use within the crate only.
+
+macro_rules! error {
+    ($($arg:tt)+) => { av_error!($($arg)+) };
+}
+
+macro_rules! warn {
+    ($($arg:tt)+) => { av_warn!($($arg)+) };
+}
+
+macro_rules! info {
+    ($($arg:tt)+) => { av_info!($($arg)+) };
+}
+
+macro_rules! debug {
+    ($($arg:tt)+) => { av_debug!($($arg)+) };
+}
+
+macro_rules! trace {
+    ($($arg:tt)+) => { av_trace!($($arg)+) };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filter_picks_the_level_of_this_crate() {
+        assert_eq!(parse_filter("debug"), Some(Level::Debug));
+        assert_eq!(parse_filter("avalanche=trace"), Some(Level::Trace));
+        assert_eq!(parse_filter("warn,avalanche=debug"), Some(Level::Debug));
+        assert_eq!(parse_filter("other=debug"), None);
+        assert_eq!(parse_filter("off"), Some(Level::Off));
+        assert_eq!(parse_filter("avalanche=loud"), None);
+    }
+}
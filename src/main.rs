@@ -1,366 +1,242 @@
-extern crate byteorder;
-extern crate hex;
-extern crate rand;
-extern crate ring;
+#[macro_use]
+extern crate avalanche;
 
-use byteorder::{LittleEndian, WriteBytesExt};
-use rand::{sample, thread_rng, Rng};
-use ring::digest;
+use avalanche::{ConsensusParams, Hash, Network, TransactionGenerator, DATA_RANGE,
+                VALID_DATA_LIMIT};
 
-use std::collections::HashMap;
-use std::sync::{mpsc::{channel, Receiver, Sender},
-                Arc,
-                Mutex};
+use std::env;
+use std::process;
+use std::str::FromStr;
 use std::thread;
+use std::time::Duration;
 
-fn main() {
-    let mut net = Network::new(10);
-    net.run();
-
-    loop {
-        let tx = Transaction::random();
-        println!("sending new transaction into the network {}", &tx.hash());
-
-        // Pick a random node in the network let the node handle the random transaction.
-        // All transactions with a number < 7 are considered invalid.
-        let id = thread_rng().gen_range(0, net.nodes.len()) as u64;
-        let node = net.nodes.get_mut(&id).unwrap();
-        node.lock()
-            .unwrap()
-            .handle_message(0, &Message::Transaction(tx));
-
-        thread::sleep_ms(500); // cpu ded
-    }
+/// Simulation parameters taken from the command line.
+struct Options {
+    nodes: u64,
+    params: ConsensusParams,
+    rate: u32,
+    count: Option<u64>,
+    valid_ratio: Option<f64>,
+    seed: Option<u64>,
+    report: Option<Format>,
+    /// Address of the status endpoint, see `avalanche::http`.
+    http: Option<String>,
 }
 
-#[derive(Eq, PartialEq, Clone, Hash)]
-struct Hash(Vec<u8>);
-
-impl Hash {
-    fn to_string(&self) -> String {
-        hex::encode(&self.0)
-    }
+/// Output format of the report printed after a bounded run.
+#[derive(Clone, Copy)]
+enum Format {
+    Json,
+    Csv,
 }
 
-impl ::std::fmt::Display for Hash {
-    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
-        write!(f, "{:?}", self.to_string())
-    }
-}
+impl FromStr for Format {
+    type Err = ();
 
-impl ::std::fmt::Debug for Hash {
-    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
-        write!(f, "{:?}", self.to_string())
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "json" => Ok(Format::Json),
+            "csv" => Ok(Format::Csv),
+            _ => Err(()),
+        }
     }
 }
 
-#[derive(Debug)]
-enum Message {
-    Query(QueryMessage),
-    QueryResponse((u64, QueryResponse)),
-    Transaction(Transaction),
-}
-
-#[derive(Debug, Clone, PartialEq)]
-enum Status {
-    Valid,
-    Invalid,
-}
-
-#[derive(Debug)]
-struct QueryResponse {
-    hash: Hash,
-    status: Status,
-}
-
-#[derive(Debug)]
-struct QueryMessage {
-    tx: Transaction,
-    status: Status,
-}
-
-#[derive(Debug, Clone)]
-struct Transaction {
-    nonce: u64,
-    /// numbers < 7 are consired valid transactions. Rest is invalid.
-    data: i32,
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            nodes: 10,
+            params: ConsensusParams::default(),
+            rate: 2,
+            count: None,
+            valid_ratio: None,
+            seed: None,
+            report: None,
+            http: None,
+        }
+    }
 }
 
-impl Transaction {
-    fn random() -> Self {
-        let mut rng = thread_rng();
-        Transaction {
-            nonce: rand::random::<u64>(),
-            data: rng.gen_range(0, 10),
+fn usage() -> String {
+    let defaults = Options::default();
+    format!(
+        "Simulate the Avalanche consensus protocol.
+
+USAGE:
+    avalanche [OPTIONS]
+
+OPTIONS:
+    -n, --nodes <N>         number of nodes in the network [default: {}]
+    -k, --samples <K>       number of nodes sampled per query [default: {}]
+    -a, --alpha <F>         fraction of a sample needed for a quorum [default: {}]
+    -c, --conviction <F>    fraction of k consecutive quorums to accept an epoch [default: {}]
+    -b, --beta <N>          number of epochs until a transaction is final [default: {}]
+    -r, --rate <N>          transactions injected per second [default: {}]
+    -t, --transactions <N>  stop after N transactions [default: unlimited]
+    -v, --valid <F>         fraction of valid transactions [default: {}]
+    -s, --seed <N>          seed of the simulation [default: $AVALANCHE_SEED or random]
+        --report <FORMAT>   print the results of a bounded run as json or csv
+        --http <ADDR>       serve the status of the network as json on ADDR
+                            (needs the http feature)
+    -h, --help              print this help",
+        defaults.nodes,
+        defaults.params.k,
+        defaults.params.alpha,
+        defaults.params.conviction,
+        defaults.params.beta,
+        defaults.rate,
+        f64::from(VALID_DATA_LIMIT) / f64::from(DATA_RANGE),
+    )
+}
+
+fn value<T: FromStr>(flag: &str, value: Option<String>) -> Result<T, String> {
+    let value = value.ok_or_else(|| format!("missing value for {}", flag))?;
+    value
+        .parse()
+        .map_err(|_| format!("invalid value for {}: {}", flag, value))
+}
+
+/// Like `value`, but rejects NaN and infinite numbers.
+fn finite<T: FromStr + Into<f64> + Copy>(flag: &str, arg: Option<String>) -> Result<T, String> {
+    let arg = arg.ok_or_else(|| format!("missing value for {}", flag))?;
+    match arg.parse::<T>() {
+        Ok(number) if number.into().is_finite() => Ok(number),
+        _ => Err(format!("invalid value for {}: {}", flag, arg)),
+    }
+}
+
+/// Parse the options, returns `Ok(None)` if help was requested.
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Option<Options>, String> {
+    let mut opts = Options::default();
+    while let Some(flag) = args.next() {
+        match flag.as_str() {
+            "-n" | "--nodes" => opts.nodes = value(&flag, args.next())?,
+            "-k" | "--samples" => opts.params.k = value(&flag, args.next())?,
+            "-a" | "--alpha" => opts.params.alpha = finite(&flag, args.next())?,
+            "-c" | "--conviction" => opts.params.conviction = finite(&flag, args.next())?,
+            "-b" | "--beta" => opts.params.beta = value(&flag, args.next())?,
+            "-r" | "--rate" => opts.rate = value(&flag, args.next())?,
+            "-t" | "--transactions" => opts.count = Some(value(&flag, args.next())?),
+            "-v" | "--valid" => opts.valid_ratio = Some(finite(&flag, args.next())?),
+            "-s" | "--seed" => opts.seed = Some(value(&flag, args.next())?),
+            "--report" => opts.report = Some(value(&flag, args.next())?),
+            "--http" => opts.http = Some(value(&flag, args.next())?),
+            "-h" | "--help" => return Ok(None),
+            _ => return Err(format!("unknown option {}", flag)),
         }
     }
-
-    fn serialize(&self) -> Vec<u8> {
-        let mut buf = vec![];
-        buf.write_u64::<LittleEndian>(self.nonce).unwrap();
-        buf
+    if opts.nodes == 0 {
+        return Err("the network needs at least one node".to_string());
     }
-
-    fn hash(&self) -> Hash {
-        let digest = digest::digest(&digest::SHA256, &self.serialize());
-        Hash(digest.as_ref().to_vec())
+    if opts.http.is_some() && !cfg!(feature = "http") {
+        return Err("--http needs the http feature".to_string());
     }
+    Ok(Some(opts))
 }
 
-/// Hardcoded tuning parameters for the algorithm.
-pub const SAMPLES: usize = 4;
-pub const MAX_EPOCHS: u32 = 4;
-pub const TRESHOLD: f32 = 0.75;
-pub const CONVICTION_TRESHOLD: f32 = 0.75;
-
-#[derive(Debug)]
-struct Network {
-    nodes: HashMap<u64, Arc<Mutex<Node>>>,
-    receiver: Arc<Mutex<Receiver<(u64, Message)>>>,
-}
+fn main() {
+    avalanche::log::init();
 
-impl Network {
-    /// Create a new network with `n` participating nodes.
-    fn new(n: u64) -> Self {
-        let (sender, receiver) = channel();
-        Network {
-            nodes: (0..n)
-                .map(|id| (id, Arc::new(Mutex::new(Node::new(id, sender.clone())))))
-                .collect(),
-            receiver: Arc::new(Mutex::new(receiver)),
+    let opts = match parse_args(env::args().skip(1)) {
+        Ok(Some(opts)) => opts,
+        Ok(None) => {
+            println!("{}", usage());
+            return;
         }
-    }
+        Err(err) => {
+            eprintln!("error: {}\n\n{}", err, usage());
+            process::exit(2);
+        }
+    };
 
-    fn run(&self) {
-        let receiver = self.receiver.clone();
-        let mut nodes = self.nodes.clone();
+    let mut net = match opts.seed {
+        Some(seed) => Network::with_params_seeded(opts.nodes, opts.params, seed),
+        None => Network::with_params(opts.nodes, opts.params),
+    };
+    av_info!("running network with seed {}", net.seed());
+    net.run();
+    let status = StatusEndpoint::bind(opts.http.as_deref());
 
-        thread::spawn(move || loop {
-            let (origin, msg) = receiver.lock().unwrap().recv().unwrap();
-            match msg {
-                Message::Query(ref _msg) => {
-                    let mut sampled = sample_nodes(&nodes, SAMPLES, origin);
-                    sampled
-                        .iter()
-                        .map(|id| {
-                            nodes
-                                .get_mut(&id)
-                                .unwrap()
-                                .lock()
-                                .unwrap()
-                                .handle_message(origin, &msg)
-                        })
-                        .collect::<Vec<_>>();
-                }
-                Message::QueryResponse((to, ref _msg)) => {
-                    let mut node = nodes.get_mut(&to).unwrap();
-                    node.lock().unwrap().handle_message(origin, &msg);
-                }
-                _ => unreachable!(),
-            }
-        });
+    let mut txs = TransactionGenerator::new(opts.rate).seed(net.seed());
+    if let Some(count) = opts.count {
+        txs = txs.count(count);
     }
-}
-
-fn sample_nodes(nodes: &HashMap<u64, Arc<Mutex<Node>>>, n: usize, excl: u64) -> Vec<u64> {
-    let ids: Vec<u64> = nodes
-        .iter()
-        .filter(|(&id, _)| id != excl)
-        .map(|(id, _)| *id)
-        .collect();
-    sample(&mut thread_rng(), ids, n)
-}
-
-#[derive(Debug, Clone)]
-struct TxState {
-    epoch: u32,
-    tx: Transaction,
-    status: Status,
-    responses: Vec<Status>,
-    is_final: bool,
-
-    /// 1. Each node maintains a counter cnt
-    /// 2. Upon every color change, the node resets cnt to 0
-    /// 3. Upon every successful query that yields ≥ αk responses for the same
-    /// color as the node, the node increments cnt.
-    cnt_valid: u32,
-    cnt_invalid: u32,
-    cnt: u32,
-
-    /// Last decided status.
-    last_status: Status,
-}
-
-impl TxState {
-    fn new(tx: Transaction, status: Status) -> Self {
-        TxState {
-            responses: Vec::new(),
-            is_final: false,
-            last_status: Status::Invalid,
-            epoch: 0,
-            cnt_valid: 0,
-            cnt_invalid: 0,
-            cnt: 0,
-            tx,
-            status,
-        }
+    if let Some(ratio) = opts.valid_ratio {
+        txs = txs.valid_ratio(ratio);
     }
 
-    fn incr_status(&mut self, s: &Status) -> u32 {
-        match s {
-            Status::Valid => {
-                self.cnt_valid += 1;
-                self.cnt_valid
-            }
-            Status::Invalid => {
-                self.cnt_invalid += 1;
-                self.cnt_invalid
-            }
+    // All transactions with a number < VALID_DATA_LIMIT are valid. Only bounded
+    // runs remember what they injected, to wait for it to be finalized.
+    let mut injected: Vec<Hash> = Vec::new();
+    for tx in txs {
+        av_info!("sending new transaction into the network {}", &tx.hash());
+        if opts.count.is_some() {
+            injected.push(tx.id().clone());
         }
+        if let Err(err) = net.inject(tx) {
+            av_error!("failed to inject transaction: {}", err);
+            return;
+        }
+        status.poll(&net);
     }
 
-    fn status_count(&self, s: &Status) -> u32 {
-        match s {
-            Status::Valid => self.cnt_valid,
-            Status::Invalid => self.cnt_invalid,
+    // A bounded run ends once every transaction got finalized.
+    while !injected.iter().all(|hash| net.is_finalized(hash)) {
+        if let Err(err) = net.health() {
+            av_error!("{}", err);
+            process::exit(1);
         }
+        status.poll(&net);
+        thread::sleep(Duration::from_millis(10));
     }
+    av_info!("finalized all {} transactions", injected.len());
 
-    fn advance(&mut self) {
-        self.epoch += 1;
-        self.responses.clear();
+    match opts.report {
+        Some(Format::Json) => println!("{}", net.report().to_json()),
+        Some(Format::Csv) => print!("{}", net.report().to_csv()),
+        None => {}
     }
 }
 
-#[derive(Debug, Clone)]
-struct Node {
-    mempool: HashMap<Hash, TxState>,
-    id: u64,
-    sender: Sender<(u64, Message)>,
-}
+/// The status endpoint of the network, if one was requested with `--http`.
+#[cfg(feature = "http")]
+struct StatusEndpoint(Option<avalanche::http::StatusServer>);
 
-impl Node {
-    fn new(id: u64, sender: Sender<(u64, Message)>) -> Self {
-        Node {
-            id,
-            sender,
-            mempool: HashMap::new(),
-        }
-    }
-
-    fn handle_message(&mut self, origin: u64, msg: &Message) {
-        //println!("node {} recv from {} => {:?}", self.id, origin, msg);
-
-        match msg {
-            Message::Query(ref msg) => self.handle_query(origin, msg),
-            Message::QueryResponse((_to, ref msg)) => {
-                if let Some((hash, status)) = self.handle_query_response(msg) {
-                    println!("node {} got decision {:?} for tx {}", self.id, status, hash);
-                };
+#[cfg(feature = "http")]
+impl StatusEndpoint {
+    fn bind(addr: Option<&str>) -> Self {
+        let server = addr.map(|addr| match avalanche::http::StatusServer::bind(addr) {
+            Ok(server) => {
+                av_info!("serving status on http://{}", addr);
+                server
             }
-            Message::Transaction(tx) => self.handle_transaction(tx),
-        }
-    }
-
-    /// Upon receiving a query, an uncolored node adopts the color in the query,
-    /// responds with that color, and initiates its own query, whereas a colored
-    /// node simply responds with its current color.
-    fn handle_query(&mut self, origin: u64, msg: &QueryMessage) {
-        // TODO: This can be so much cleaner, just fighting to much with compiler!!
-        let state = if !self.mempool.contains_key(&msg.tx.hash()) {
-            let state = TxState::new(msg.tx.clone(), msg.status.clone());
-            self.mempool.insert(msg.tx.hash(), state.clone());
-            self.send_query(msg.tx.clone(), msg.status.clone());
-            state
-        } else {
-            let state = self.mempool.get(&msg.tx.hash()).unwrap();
-            state.clone()
-        };
-        self.send_response(origin, state.tx.hash(), state.status.clone());
-    }
-
-    /// If k responses are not received within a time bound, the node picks an
-    /// additional sample from the remaining nodes uniformly at random and queries
-    /// them until it collects all responses.     
-    /// TODO: timeout + error handling + factor some pieces out of this method!
-    fn handle_query_response(&mut self, msg: &QueryResponse) -> Option<(Hash, Status)> {
-        {
-            let state = self.mempool.get_mut(&msg.hash).unwrap();
-            // If the state is considered final we dont handle this response anymore.
-            if state.is_final {
-                return None;
+            Err(err) => {
+                av_error!("failed to serve status on {}: {}", addr, err);
+                process::exit(1);
             }
-            state.responses.push(msg.status.clone());
-
-            let n = state
-                .responses
-                .iter()
-                .filter(|&status| status == &msg.status)
-                .count();
-
-            if n >= (TRESHOLD * SAMPLES as f32) as usize {
-                // Increment the confidence of the received status.
-                let cnt = state.incr_status(&msg.status);
-                // Get the confidence of our current status.
-                let our_status_cnt = state.status_count(&state.status);
-
-                // If the confidence of the received status is higher then ours we
-                // flip to that status.
-                if cnt > our_status_cnt {
-                    state.status = msg.status.clone();
-                    state.last_status = state.status.clone();
-                }
+        });
+        StatusEndpoint(server)
+    }
 
-                if msg.status != state.last_status {
-                    state.last_status = msg.status.clone();
-                    state.cnt = 0;
-                } else {
-                    state.cnt += 1;
-                    // We only accept the color (move to the next epoch) if the
-                    // counter is higher the the conviction treshold.
-                    if state.cnt > (CONVICTION_TRESHOLD * SAMPLES as f32) as u32 {
-                        state.advance();
-                        if state.epoch == MAX_EPOCHS {
-                            state.is_final = true;
-                            return Some((state.tx.hash(), state.status.clone()));
-                        }
-                    }
-                }
+    fn poll(&self, net: &Network) {
+        if let Some(ref server) = self.0 {
+            if let Err(err) = server.poll(net) {
+                av_warn!("failed to answer status requests: {}", err);
             }
         }
-
-        let state = self.mempool.get(&msg.hash).unwrap();
-        self.send_query(state.tx.clone(), state.status.clone());
-        None
-    }
-
-    fn handle_transaction(&mut self, tx: &Transaction) {
-        // Verify transaction ourself.
-        let status = self.verify_transaction(tx);
-
-        // Add the tx to our mempool.
-        self.mempool
-            .insert(tx.hash(), TxState::new(tx.clone(), status.clone()));
-        self.send_query(tx.clone(), status.clone());
     }
+}
 
-    fn send_query(&self, tx: Transaction, status: Status) {
-        let msg = Message::Query(QueryMessage {
-            tx: tx,
-            status: status,
-        });
-        self.sender.send((self.id, msg));
-    }
+/// Without the http feature `--http` is rejected, so there is nothing to
+/// serve.
+#[cfg(not(feature = "http"))]
+struct StatusEndpoint;
 
-    fn send_response(&self, to: u64, hash: Hash, status: Status) {
-        let msg = Message::QueryResponse((to, QueryResponse { hash, status }));
-        self.sender.send((self.id, msg));
+#[cfg(not(feature = "http"))]
+impl StatusEndpoint {
+    fn bind(_: Option<&str>) -> Self {
+        StatusEndpoint
     }
 
-    fn verify_transaction(&self, tx: &Transaction) -> Status {
-        match tx.data < 7 {
-            true => Status::Valid,
-            false => Status::Invalid,
-        }
-    }
+    fn poll(&self, _: &Network) {}
 }
@@ -0,0 +1,212 @@
+//! TCP transport for nodes running in separate processes, enabled with the
+//! `net` feature. Every message travels as a frame: the serialized
+//! `Envelope` prefixed with its length as a little endian u32. A message
+//! opens its own connection, which is closed once the frame is written.
+
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{channel, Receiver};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use rand::RngCore;
+
+use {invalid_data, read_message, seeded_rng, write_message, AvalancheError, Candidate,
+     ConsensusParams, Envelope, Message, Node, Result, SamplingStrategy, SimRng, StakeWeighted};
+
+/// Frames longer than this are rejected instead of allocated.
+pub const MAX_FRAME_LEN: u32 = 16 << 20;
+
+/// Write `envelope` as a single length prefixed frame.
+pub fn write_frame<W: Write>(w: &mut W, envelope: &Envelope) -> io::Result<()> {
+    let mut buf = Vec::new();
+    buf.write_u64::<LittleEndian>(envelope.seq)?;
+    buf.write_u64::<LittleEndian>(envelope.origin)?;
+    write_message(&mut buf, &envelope.msg)?;
+    w.write_u32::<LittleEndian>(buf.len() as u32)?;
+    w.write_all(&buf)
+}
+
+/// Read a frame written by `write_frame`.
+pub fn read_frame<R: Read>(r: &mut R) -> io::Result<Envelope> {
+    let len = r.read_u32::<LittleEndian>()?;
+    if len > MAX_FRAME_LEN {
+        return Err(invalid_data("frame too long"));
+    }
+    let mut buf = vec![0; len as usize];
+    r.read_exact(&mut buf)?;
+    let mut r = &buf[..];
+    let seq = r.read_u64::<LittleEndian>()?;
+    let origin = r.read_u64::<LittleEndian>()?;
+    let msg = read_message(&mut r)?;
+    if !r.is_empty() {
+        return Err(invalid_data("trailing bytes after message"));
+    }
+    Ok(Envelope { seq, origin, msg })
+}
+
+/// A node listening on a TCP socket, sending its messages to the peers it
+/// was told about. Queries are sampled from those peers with a stake of 1
+/// each, since the stake of remote nodes is unknown.
+#[derive(Debug)]
+pub struct TcpNode {
+    node: Node,
+    listener: TcpListener,
+    peers: BTreeMap<u64, SocketAddr>,
+    /// Messages the node sent, waiting for `flush`.
+    outbox: Receiver<Envelope>,
+    rng: SimRng,
+}
+
+impl TcpNode {
+    /// Create node `id` listening on `addr`, e.g. `127.0.0.1:0` for a free
+    /// port on the loopback interface.
+    pub fn bind<A: ToSocketAddrs>(id: u64, addr: A, params: ConsensusParams) -> Result<Self> {
+        let (sender, outbox) = channel();
+        Ok(TcpNode {
+            node: Node::new_with_mempool(id, sender, params, Vec::new()),
+            listener: TcpListener::bind(addr)?,
+            peers: BTreeMap::new(),
+            outbox,
+            rng: seeded_rng(id),
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Let the node send to and sample node `id` listening on `addr`.
+    pub fn add_peer(&mut self, id: u64, addr: SocketAddr) {
+        self.peers.insert(id, addr);
+    }
+
+    pub fn node(&self) -> &Node {
+        &self.node
+    }
+
+    /// Let the node handle a message as if node `origin` sent it, e.g. a new
+    /// transaction, and send whatever it sends in turn.
+    pub fn handle(&mut self, origin: u64, msg: &Message) -> Result<usize> {
+        self.node.handle_message(origin, msg)?;
+        self.flush()
+    }
+
+    /// Accept a single connection, handle every frame of it and send
+    /// whatever the node sends in turn. Blocks until a peer connects.
+    /// Returns the number of messages sent.
+    pub fn serve_one(&mut self) -> Result<usize> {
+        let (mut stream, _) = self.listener.accept()?;
+        loop {
+            let envelope = match read_frame(&mut stream) {
+                Ok(envelope) => envelope,
+                Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(err) => return Err(err.into()),
+            };
+            self.node.handle_message(envelope.origin, &envelope.msg)?;
+        }
+        self.flush()
+    }
+
+    /// Send every message the node sent since the last flush to its
+    /// recipients. Returns the number of frames written.
+    pub fn flush(&mut self) -> Result<usize> {
+        let mut sent = 0;
+        while let Ok(envelope) = self.outbox.try_recv() {
+            sent += self.route(envelope)?;
+        }
+        Ok(sent)
+    }
+
+    /// Send a sampled query or batch to its sample and a response to the
+    /// node it answers.
+    fn route(&mut self, envelope: Envelope) -> Result<usize> {
+        let (seq, origin) = (envelope.seq, envelope.origin);
+        let (msg, to) = match envelope.msg {
+            Message::Query(mut query) => {
+                let sampled = self.sample(&query.exclude, query.samples);
+                let responded = query.exclude.iter().filter(|id| self.peers.contains_key(id));
+                query.sampled_stake = (sampled.len() + responded.count()) as u64;
+                (Message::Query(query), sampled)
+            }
+            Message::QueryBatch(mut batch) => {
+                let sampled = self.sample(&[], batch.samples);
+                batch.sampled_stake = sampled.len() as u64;
+                (Message::QueryBatch(batch), sampled)
+            }
+            Message::MempoolRequest => (Message::MempoolRequest, self.sample(&[], 1)),
+            msg @ Message::QueryResponse((to, _))
+            | msg @ Message::QueryBatchResponse((to, _))
+            | msg @ Message::Decided((to, _, _))
+            | msg @ Message::MempoolDigest((to, _))
+            | msg @ Message::Pull((to, _))
+            | msg @ Message::Pulled((to, _)) => (msg, vec![to]),
+            Message::Transaction(_) | Message::Transactions(_) => return Ok(0),
+        };
+        let envelope = Envelope { seq, origin, msg };
+        for id in &to {
+            let addr = self.peers.get(id).ok_or(AvalancheError::UnknownNode(*id))?;
+            let mut stream = TcpStream::connect(addr)?;
+            write_frame(&mut stream, &envelope)?;
+        }
+        Ok(to.len())
+    }
+
+    fn sample(&mut self, exclude: &[u64], samples: usize) -> Vec<u64> {
+        let candidates = self
+            .peers
+            .keys()
+            .filter(|id| **id != self.node.id())
+            .map(|id| Candidate { id: *id, stake: 1 })
+            .collect::<Vec<_>>();
+        let rng: &mut dyn RngCore = &mut self.rng;
+        StakeWeighted.sample(rng, self.node.id(), &candidates, samples, exclude)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {Status, Transaction};
+
+    #[test]
+    fn frame_round_trips() {
+        let tx = Transaction::random_from(&mut seeded_rng(1));
+        let envelope = Envelope {
+            seq: 7,
+            origin: 3,
+            msg: Message::Transaction(tx.clone()),
+        };
+        let mut buf = Vec::new();
+        write_frame(&mut buf, &envelope).unwrap();
+        let read = read_frame(&mut &buf[..]).unwrap();
+        assert_eq!((read.seq, read.origin), (7, 3));
+        match read.msg {
+            Message::Transaction(ref read) => assert_eq!(read, &tx),
+            ref msg => panic!("expected a transaction, got {:?}", msg),
+        }
+    }
+
+    #[test]
+    fn query_and_response_travel_over_loopback() {
+        let params = ConsensusParams::default();
+        let mut a = TcpNode::bind(0, "127.0.0.1:0", params).unwrap();
+        let mut b = TcpNode::bind(1, "127.0.0.1:0", params).unwrap();
+        a.add_peer(1, b.local_addr().unwrap());
+        b.add_peer(0, a.local_addr().unwrap());
+        let mut tx = Transaction::random_from(&mut seeded_rng(1));
+        tx.data = 0;
+        let hash = tx.id().clone();
+
+        // The query of node 0 reaches node 1, which answers and starts
+        // querying node 0 itself.
+        assert_eq!(a.handle(0, &Message::Transaction(tx)).unwrap(), 1);
+        assert_eq!(b.serve_one().unwrap(), 2);
+        assert_eq!(b.node().tx_state(&hash).unwrap().status(), &Status::Valid);
+        a.serve_one().unwrap();
+        a.serve_one().unwrap();
+        // A sample of one node makes a single response a quorum.
+        let state = a.node().tx_state(&hash).unwrap();
+        assert_eq!((state.status(), state.cnt()), (&Status::Valid, 1));
+    }
+}
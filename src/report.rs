@@ -0,0 +1,120 @@
+//! Machine readable results of a simulation run, see `Network::report`.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::time::Duration;
+
+use {lock_node, Hash, Network, Status};
+
+/// Outcome of a single transaction over all nodes of the network.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TxReport {
+    pub hash: Hash,
+    /// Status the transaction was first finalized with, `None` if no node
+    /// finalized it yet.
+    pub status: Option<Status>,
+    /// Highest epoch any node reached for the transaction.
+    pub epochs: u32,
+    /// Number of nodes that finalized the transaction.
+    pub finalized_by: usize,
+    pub queries: u64,
+    pub responses: u64,
+    /// Time until the last of the nodes that finalized the transaction did.
+    pub latency: Option<Duration>,
+}
+
+/// Per transaction results of a simulation, ordered by hash.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SimulationReport {
+    pub transactions: Vec<TxReport>,
+}
+
+impl SimulationReport {
+    /// Collect the results of every transaction known to any node, except
+    /// the genesis every node starts with.
+    pub(crate) fn collect(net: &Network) -> Self {
+        let finalized = net.finalized();
+        let stats = net.message_stats();
+        let mut txs: BTreeMap<[u8; 32], TxReport> = BTreeMap::new();
+        for node in net.nodes().values() {
+            let node = lock_node(node);
+            for (hash, state) in &node.mempool {
+                if state.tx.is_genesis() {
+                    continue;
+                }
+                let report = txs.entry(*hash.as_bytes()).or_insert_with(|| {
+                    let count = stats.get(hash);
+                    TxReport {
+                        hash: hash.clone(),
+                        status: finalized.get(hash).cloned(),
+                        epochs: 0,
+                        finalized_by: 0,
+                        queries: count.queries,
+                        responses: count.responses,
+                        latency: None,
+                    }
+                });
+                report.epochs = report.epochs.max(state.epoch);
+                if state.is_final {
+                    report.finalized_by += 1;
+                }
+                report.latency = report.latency.max(state.latency());
+            }
+        }
+        SimulationReport {
+            transactions: txs.into_values().collect(),
+        }
+    }
+
+    /// Encode the report as a JSON object with a `transactions` array.
+    /// Missing values are `null`, latencies are in milliseconds.
+    pub fn to_json(&self) -> String {
+        let mut out = String::from("{\"transactions\":[");
+        for (i, tx) in self.transactions.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            write!(
+                out,
+                "{{\"hash\":\"{}\",\"status\":{},\"epochs\":{},\"finalized_by\":{},\
+                 \"queries\":{},\"responses\":{},\"latency_ms\":{}}}",
+                tx.hash,
+                tx.status
+                    .as_ref()
+                    .map_or("null".to_string(), |s| format!("\"{:?}\"", s)),
+                tx.epochs,
+                tx.finalized_by,
+                tx.queries,
+                tx.responses,
+                tx.latency.map_or("null".to_string(), |l| millis(l).to_string()),
+            ).unwrap();
+        }
+        out.push_str("]}");
+        out
+    }
+
+    /// Encode the report as CSV with a header line. Missing values are left
+    /// empty, latencies are in milliseconds.
+    pub fn to_csv(&self) -> String {
+        let mut out =
+            String::from("hash,status,epochs,finalized_by,queries,responses,latency_ms\n");
+        for tx in &self.transactions {
+            writeln!(
+                out,
+                "{},{},{},{},{},{},{}",
+                tx.hash,
+                tx.status.as_ref().map_or(String::new(), |s| format!("{:?}", s)),
+                tx.epochs,
+                tx.finalized_by,
+                tx.queries,
+                tx.responses,
+                tx.latency.map_or(String::new(), |l| millis(l).to_string()),
+            ).unwrap();
+        }
+        out
+    }
+}
+
+fn millis(d: Duration) -> f64 {
+    d.as_secs_f64() * 1000.0
+}
@@ -0,0 +1,428 @@
+//! Checkpoints of the consensus state of a network, see `Network::snapshot`.
+
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use rand::SeedableRng;
+
+use {invalid_data, lock_node, read_hash, read_reason, read_status, write_reason, write_status,
+     Behavior, ColorPolicy, ConflictId, ConsensusMode, ConsensusParams, Hash, Ledger, Network,
+     Node, SafetyViolation, SimRng, Status, Transaction, TxState, Vote};
+
+/// The mempools, conflict sets and decisions of every node of a network.
+/// Messages in flight are not part of the snapshot, nor are the latency,
+/// topology, sampling strategy, clock and validators of the network, so
+/// take it from a network that is not running and configure those again
+/// after restoring it.
+#[derive(Debug, Clone)]
+pub struct NetworkSnapshot {
+    params: ConsensusParams,
+    seed: u64,
+    nodes: Vec<NodeSnapshot>,
+    finalized: Vec<(Hash, (u64, Status))>,
+    violations: Vec<SafetyViolation>,
+}
+
+#[derive(Debug, Clone)]
+struct NodeSnapshot {
+    id: u64,
+    behavior: Behavior,
+    stake: u64,
+    response_delay: Duration,
+    failed: bool,
+    mempool: Vec<TxSnapshot>,
+    /// Every conflict set in the order its transactions were seen, which
+    /// decides the preferred transaction.
+    conflict_sets: Vec<(ConflictId, Vec<Hash>)>,
+}
+
+/// A transaction state with its timestamps as the time passed since them
+/// when the snapshot was taken.
+#[derive(Debug, Clone)]
+struct TxSnapshot {
+    state: TxState,
+    created: Duration,
+    queried: Duration,
+    finalized: Option<Duration>,
+}
+
+impl NetworkSnapshot {
+    pub(crate) fn capture(net: &Network) -> Self {
+        let nodes = net
+            .nodes()
+            .values()
+            .map(|node| {
+                let node = lock_node(node);
+                let mut mempool = node
+                    .mempool
+                    .values()
+                    .map(|state| {
+                        let now = state.clock.now();
+                        TxSnapshot {
+                            state: state.clone(),
+                            created: now.saturating_duration_since(state.created_at),
+                            queried: now.saturating_duration_since(state.queried_at),
+                            finalized: state
+                                .finalized_at
+                                .map(|at| now.saturating_duration_since(at)),
+                        }
+                    })
+                    .collect::<Vec<_>>();
+                mempool.sort_by_key(|tx| tx.state.tx.id().0);
+                let conflict_sets = node
+                    .conflict_sets
+                    .iter()
+                    .map(|(id, set)| (*id, set.clone()))
+                    .collect::<BTreeMap<_, _>>();
+                NodeSnapshot {
+                    id: node.id,
+                    behavior: node.behavior,
+                    stake: node.stake,
+                    response_delay: node.response_delay,
+                    failed: node.failed,
+                    mempool,
+                    conflict_sets: conflict_sets.into_iter().collect(),
+                }
+            })
+            .collect();
+        let ledger = net.decisions.lock().unwrap();
+        let mut finalized = ledger
+            .finalized
+            .iter()
+            .map(|(hash, first)| (hash.clone(), first.clone()))
+            .collect::<Vec<_>>();
+        finalized.sort_by_key(|(hash, _)| hash.0);
+        NetworkSnapshot {
+            params: net.params,
+            seed: net.seed,
+            nodes,
+            finalized,
+            violations: ledger.violations.clone(),
+        }
+    }
+
+    /// Build a stopped network from the snapshot. Nodes draw their random
+    /// numbers from the seed of the snapshotted network again.
+    pub(crate) fn restore(&self) -> Network {
+        let net = Network::with_params_seeded(0, self.params, self.seed);
+        {
+            let mut nodes = net.nodes.write().unwrap();
+            let mut rng = net.rng.lock().unwrap();
+            for snapshot in &self.nodes {
+                let node_rng = SimRng::from_rng(&mut *rng).unwrap();
+                let (decisions, observers) = (net.decisions.clone(), net.observers.clone());
+                let sender = net.sender.clone();
+                let mut node =
+                    Node::new(snapshot.id, sender, self.params, node_rng, decisions, observers);
+                node.behavior = snapshot.behavior;
+                node.stake = snapshot.stake;
+                node.response_delay = snapshot.response_delay;
+                node.failed = snapshot.failed;
+                node.mempool.clear();
+                for tx in &snapshot.mempool {
+                    let mut state = tx.state.clone().with_clock(node.clock.clone());
+                    let now = state.clock.now();
+                    let ago = |duration| now.checked_sub(duration).unwrap_or(now);
+                    state.created_at = ago(tx.created);
+                    state.queried_at = ago(tx.queried);
+                    state.finalized_at = tx.finalized.map(ago);
+                    node.mempool.insert(state.tx.id().clone(), state);
+                }
+                node.conflict_sets = snapshot.conflict_sets.iter().cloned().collect();
+                nodes.insert(snapshot.id, Arc::new(Mutex::new(node)));
+            }
+        }
+        let mut ledger = Ledger {
+            finalized: self.finalized.iter().cloned().collect(),
+            violations: self.violations.clone(),
+            members: self.nodes.len(),
+            ..Ledger::default()
+        };
+        for node in &self.nodes {
+            for tx in node.mempool.iter().filter(|tx| tx.state.is_final) {
+                ledger.count(tx.state.tx.id(), &tx.state.status);
+            }
+        }
+        *net.decisions.lock().unwrap() = ledger;
+        net
+    }
+
+    /// Number of nodes in the snapshot.
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Write the snapshot in its binary format.
+    pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        write_params(w, &self.params)?;
+        w.write_u64::<LittleEndian>(self.seed)?;
+        w.write_u64::<LittleEndian>(self.nodes.len() as u64)?;
+        for node in &self.nodes {
+            write_node(w, node)?;
+        }
+        w.write_u64::<LittleEndian>(self.finalized.len() as u64)?;
+        for (hash, (node, status)) in &self.finalized {
+            w.write_all(hash.as_bytes())?;
+            w.write_u64::<LittleEndian>(*node)?;
+            write_status(w, status)?;
+        }
+        w.write_u64::<LittleEndian>(self.violations.len() as u64)?;
+        for violation in &self.violations {
+            w.write_all(violation.hash.as_bytes())?;
+            for (node, status) in &[&violation.first, &violation.conflicting] {
+                w.write_u64::<LittleEndian>(*node)?;
+                write_status(w, status)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Read a snapshot written by `write_to`.
+    pub fn read_from<R: Read>(r: &mut R) -> io::Result<NetworkSnapshot> {
+        let params = read_params(r)?;
+        let seed = r.read_u64::<LittleEndian>()?;
+        let mut nodes = Vec::new();
+        for _ in 0..r.read_u64::<LittleEndian>()? {
+            nodes.push(read_node(r, params)?);
+        }
+        let mut finalized = Vec::new();
+        for _ in 0..r.read_u64::<LittleEndian>()? {
+            let hash = read_hash(r)?;
+            let node = r.read_u64::<LittleEndian>()?;
+            finalized.push((hash, (node, read_status(r)?)));
+        }
+        let mut violations = Vec::new();
+        for _ in 0..r.read_u64::<LittleEndian>()? {
+            let hash = read_hash(r)?;
+            let first = (r.read_u64::<LittleEndian>()?, read_status(r)?);
+            let conflicting = (r.read_u64::<LittleEndian>()?, read_status(r)?);
+            violations.push(SafetyViolation {
+                hash,
+                first,
+                conflicting,
+            });
+        }
+        Ok(NetworkSnapshot {
+            params,
+            seed,
+            nodes,
+            finalized,
+            violations,
+        })
+    }
+}
+
+fn write_params<W: Write>(w: &mut W, params: &ConsensusParams) -> io::Result<()> {
+    w.write_u64::<LittleEndian>(params.k as u64)?;
+    w.write_f32::<LittleEndian>(params.alpha)?;
+    w.write_f32::<LittleEndian>(params.conviction)?;
+    w.write_u32::<LittleEndian>(params.beta)?;
+    w.write_u8(match params.mode {
+        ConsensusMode::Snowflake => 0,
+        ConsensusMode::Snowball => 1,
+    })?;
+    write_option(w, params.mempool_capacity.map(|capacity| capacity as u64))?;
+    write_option(w, params.channel_capacity.map(|capacity| capacity as u64))?;
+    write_option(w, params.ttl.map(|ttl| ttl.as_nanos() as u64))?;
+    w.write_u8(params.diagnostics as u8)?;
+    w.write_u8(params.share_decisions as u8)?;
+    w.write_u8(params.vote_history as u8)?;
+    w.write_u8(match params.initial_color {
+        ColorPolicy::Adopt => 0,
+        ColorPolicy::Verify => 1,
+    })
+}
+
+fn read_params<R: Read>(r: &mut R) -> io::Result<ConsensusParams> {
+    Ok(ConsensusParams {
+        k: r.read_u64::<LittleEndian>()? as usize,
+        alpha: r.read_f32::<LittleEndian>()?,
+        conviction: r.read_f32::<LittleEndian>()?,
+        beta: r.read_u32::<LittleEndian>()?,
+        mode: match r.read_u8()? {
+            0 => ConsensusMode::Snowflake,
+            1 => ConsensusMode::Snowball,
+            _ => return Err(invalid_data("invalid consensus mode")),
+        },
+        mempool_capacity: read_option(r)?.map(|capacity| capacity as usize),
+        channel_capacity: read_option(r)?.map(|capacity| capacity as usize),
+        ttl: read_option(r)?.map(Duration::from_nanos),
+        diagnostics: read_bool(r)?,
+        share_decisions: read_bool(r)?,
+        vote_history: read_bool(r)?,
+        initial_color: match r.read_u8()? {
+            0 => ColorPolicy::Adopt,
+            1 => ColorPolicy::Verify,
+            _ => return Err(invalid_data("invalid color policy")),
+        },
+    })
+}
+
+fn write_node<W: Write>(w: &mut W, node: &NodeSnapshot) -> io::Result<()> {
+    w.write_u64::<LittleEndian>(node.id)?;
+    w.write_u8(match node.behavior {
+        Behavior::Honest => 0,
+        Behavior::AlwaysValid => 1,
+        Behavior::AlwaysInvalid => 2,
+        Behavior::Random => 3,
+        Behavior::Flip => 4,
+    })?;
+    w.write_u64::<LittleEndian>(node.stake)?;
+    w.write_u64::<LittleEndian>(node.response_delay.as_nanos() as u64)?;
+    w.write_u8(node.failed as u8)?;
+    w.write_u64::<LittleEndian>(node.mempool.len() as u64)?;
+    for tx in &node.mempool {
+        write_tx(w, tx)?;
+    }
+    w.write_u64::<LittleEndian>(node.conflict_sets.len() as u64)?;
+    for (id, set) in &node.conflict_sets {
+        w.write_u64::<LittleEndian>(*id)?;
+        w.write_u32::<LittleEndian>(set.len() as u32)?;
+        for hash in set {
+            w.write_all(hash.as_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+fn read_node<R: Read>(r: &mut R, params: ConsensusParams) -> io::Result<NodeSnapshot> {
+    let id = r.read_u64::<LittleEndian>()?;
+    let behavior = match r.read_u8()? {
+        0 => Behavior::Honest,
+        1 => Behavior::AlwaysValid,
+        2 => Behavior::AlwaysInvalid,
+        3 => Behavior::Random,
+        4 => Behavior::Flip,
+        _ => return Err(invalid_data("invalid behavior")),
+    };
+    let stake = r.read_u64::<LittleEndian>()?;
+    let response_delay = Duration::from_nanos(r.read_u64::<LittleEndian>()?);
+    let failed = read_bool(r)?;
+    let mut mempool = Vec::new();
+    for _ in 0..r.read_u64::<LittleEndian>()? {
+        mempool.push(read_tx(r, params)?);
+    }
+    let mut conflict_sets = Vec::new();
+    for _ in 0..r.read_u64::<LittleEndian>()? {
+        let id = r.read_u64::<LittleEndian>()?;
+        let mut set = Vec::new();
+        for _ in 0..r.read_u32::<LittleEndian>()? {
+            set.push(read_hash(r)?);
+        }
+        conflict_sets.push((id, set));
+    }
+    Ok(NodeSnapshot {
+        id,
+        behavior,
+        stake,
+        response_delay,
+        failed,
+        mempool,
+        conflict_sets,
+    })
+}
+
+fn write_tx<W: Write>(w: &mut W, tx: &TxSnapshot) -> io::Result<()> {
+    let state = &tx.state;
+    state.tx.write_to(w)?;
+    write_status(w, &state.status)?;
+    w.write_u32::<LittleEndian>(state.epoch)?;
+    w.write_u32::<LittleEndian>(state.cnt_valid)?;
+    w.write_u32::<LittleEndian>(state.cnt_invalid)?;
+    w.write_u32::<LittleEndian>(state.cnt)?;
+    w.write_u32::<LittleEndian>(state.quorums)?;
+    w.write_u8(state.is_final as u8)?;
+    w.write_u8(state.is_expired as u8)?;
+    write_reason(w, state.reason)?;
+    w.write_u32::<LittleEndian>(state.responses.len() as u32)?;
+    for (id, (status, stake)) in &state.responses {
+        w.write_u64::<LittleEndian>(*id)?;
+        write_status(w, status)?;
+        w.write_u64::<LittleEndian>(*stake)?;
+    }
+    w.write_u64::<LittleEndian>(state.sampled_stake)?;
+    w.write_u32::<LittleEndian>(state.peer_epochs.len() as u32)?;
+    for (epoch, count) in &state.peer_epochs {
+        w.write_u32::<LittleEndian>(*epoch)?;
+        w.write_u64::<LittleEndian>(*count as u64)?;
+    }
+    w.write_u32::<LittleEndian>(state.votes.len() as u32)?;
+    for vote in &state.votes {
+        w.write_u64::<LittleEndian>(vote.responder)?;
+        write_status(w, &vote.status)?;
+        w.write_u32::<LittleEndian>(vote.epoch)?;
+    }
+    w.write_u64::<LittleEndian>(tx.created.as_nanos() as u64)?;
+    w.write_u64::<LittleEndian>(tx.queried.as_nanos() as u64)?;
+    write_option(w, tx.finalized.map(|ago| ago.as_nanos() as u64))
+}
+
+fn read_tx<R: Read>(r: &mut R, params: ConsensusParams) -> io::Result<TxSnapshot> {
+    let tx = Transaction::read_from(r)?;
+    let status = read_status(r)?;
+    let mut state = TxState::new(tx, status, params);
+    state.epoch = r.read_u32::<LittleEndian>()?;
+    state.cnt_valid = r.read_u32::<LittleEndian>()?;
+    state.cnt_invalid = r.read_u32::<LittleEndian>()?;
+    state.cnt = r.read_u32::<LittleEndian>()?;
+    state.quorums = r.read_u32::<LittleEndian>()?;
+    state.is_final = read_bool(r)?;
+    state.is_expired = read_bool(r)?;
+    state.reason = read_reason(r)?;
+    for _ in 0..r.read_u32::<LittleEndian>()? {
+        let id = r.read_u64::<LittleEndian>()?;
+        let status = read_status(r)?;
+        state.responses.insert(id, (status, r.read_u64::<LittleEndian>()?));
+    }
+    state.sampled_stake = r.read_u64::<LittleEndian>()?;
+    for _ in 0..r.read_u32::<LittleEndian>()? {
+        let epoch = r.read_u32::<LittleEndian>()?;
+        state.peer_epochs.insert(epoch, r.read_u64::<LittleEndian>()? as usize);
+    }
+    for _ in 0..r.read_u32::<LittleEndian>()? {
+        state.votes.push(Vote {
+            responder: r.read_u64::<LittleEndian>()?,
+            status: read_status(r)?,
+            epoch: r.read_u32::<LittleEndian>()?,
+        });
+    }
+    Ok(TxSnapshot {
+        state,
+        created: Duration::from_nanos(r.read_u64::<LittleEndian>()?),
+        queried: Duration::from_nanos(r.read_u64::<LittleEndian>()?),
+        finalized: read_option(r)?.map(Duration::from_nanos),
+    })
+}
+
+fn write_option<W: Write>(w: &mut W, value: Option<u64>) -> io::Result<()> {
+    match value {
+        Some(value) => {
+            w.write_u8(1)?;
+            w.write_u64::<LittleEndian>(value)
+        }
+        None => w.write_u8(0),
+    }
+}
+
+fn read_option<R: Read>(r: &mut R) -> io::Result<Option<u64>> {
+    match r.read_u8()? {
+        0 => Ok(None),
+        1 => Ok(Some(r.read_u64::<LittleEndian>()?)),
+        _ => Err(invalid_data("invalid option flag")),
+    }
+}
+
+fn read_bool<R: Read>(r: &mut R) -> io::Result<bool> {
+    match r.read_u8()? {
+        0 => Ok(false),
+        1 => Ok(true),
+        _ => Err(invalid_data("invalid bool")),
+    }
+}
@@ -0,0 +1,156 @@
+//! Parameter sweeps to compare how fast networks converge, e.g. to
+//! reproduce the figures of the Avalanche paper, see `sweep`.
+
+use std::fmt::Write;
+
+use {seeded_rng, ConsensusParams, MessageCount, Network, Result, Transaction};
+
+/// The parameter values to combine in a sweep. Every combination of `k`,
+/// `alpha`, `beta` and `nodes` is simulated once.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SweepGrid {
+    pub k: Vec<usize>,
+    pub alpha: Vec<f32>,
+    pub beta: Vec<u32>,
+    /// Number of nodes in the network.
+    pub nodes: Vec<u64>,
+    /// Parameters of every run apart from the swept ones.
+    pub params: ConsensusParams,
+    /// Number of transactions injected into every network.
+    pub transactions: usize,
+    /// Seed of every run, so runs only differ in their parameters.
+    pub seed: u64,
+}
+
+impl Default for SweepGrid {
+    fn default() -> Self {
+        let params = ConsensusParams::default();
+        SweepGrid {
+            k: vec![params.k],
+            alpha: vec![params.alpha],
+            beta: vec![params.beta],
+            nodes: vec![10],
+            params,
+            transactions: 10,
+            seed: 0,
+        }
+    }
+}
+
+/// Outcome of the run with a single combination of parameters.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SweepRow {
+    pub k: usize,
+    pub alpha: f32,
+    pub beta: u32,
+    pub nodes: u64,
+    /// Number of transactions finalized by at least one node.
+    pub finalized: usize,
+    /// Average epoch nodes finalized their transactions in, `None` if no
+    /// node finalized anything.
+    pub avg_epochs: Option<f64>,
+    /// Query and response messages delivered over the whole run.
+    pub messages: MessageCount,
+}
+
+/// Results of a sweep, one row per combination in the order of the grid.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SweepTable {
+    pub rows: Vec<SweepRow>,
+}
+
+impl SweepTable {
+    /// Encode the table as CSV with a header line. Missing values are left
+    /// empty.
+    pub fn to_csv(&self) -> String {
+        let mut out =
+            String::from("k,alpha,beta,nodes,finalized,avg_epochs,queries,responses\n");
+        for row in &self.rows {
+            writeln!(
+                out,
+                "{},{},{},{},{},{},{},{}",
+                row.k,
+                row.alpha,
+                row.beta,
+                row.nodes,
+                row.finalized,
+                row.avg_epochs.map_or(String::new(), |epochs| epochs.to_string()),
+                row.messages.queries,
+                row.messages.responses,
+            ).unwrap();
+        }
+        out
+    }
+}
+
+/// Simulate a network for every combination of the grid until it is
+/// quiescent and collect how it converged. Runs are deterministic, the
+/// same grid always gives the same table.
+pub fn sweep(grid: &SweepGrid) -> Result<SweepTable> {
+    let mut table = SweepTable::default();
+    for &nodes in &grid.nodes {
+        for &k in &grid.k {
+            for &alpha in &grid.alpha {
+                for &beta in &grid.beta {
+                    let params = ConsensusParams {
+                        k,
+                        alpha,
+                        beta,
+                        ..grid.params
+                    };
+                    table.rows.push(run(nodes, params, grid)?);
+                }
+            }
+        }
+    }
+    Ok(table)
+}
+
+fn run(nodes: u64, params: ConsensusParams, grid: &SweepGrid) -> Result<SweepRow> {
+    info!(
+        "sweep: nodes={} k={} alpha={} beta={}",
+        nodes, params.k, params.alpha, params.beta
+    );
+    let net = Network::with_params_seeded(nodes, params, grid.seed);
+    let mut rng = seeded_rng(grid.seed);
+    for _ in 0..grid.transactions {
+        net.inject(Transaction::random_from(&mut rng))?;
+    }
+    net.run_until_quiescent()?;
+    let stats = net.stats();
+    Ok(SweepRow {
+        k: params.k,
+        alpha: params.alpha,
+        beta: params.beta,
+        nodes,
+        finalized: stats.finalized,
+        avg_epochs: stats.avg_epochs,
+        messages: stats.messages,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tiny_grid_converges_in_every_combination() {
+        let grid = SweepGrid {
+            beta: vec![2, 4],
+            nodes: vec![5, 10],
+            transactions: 3,
+            seed: 1,
+            ..SweepGrid::default()
+        };
+        let table = sweep(&grid).unwrap();
+        let combinations: Vec<_> = table.rows.iter().map(|row| (row.nodes, row.beta)).collect();
+        assert_eq!(combinations, vec![(5, 2), (5, 4), (10, 2), (10, 4)]);
+        for row in &table.rows {
+            assert_eq!(row.finalized, grid.transactions, "{:?}", row);
+            assert_eq!(row.avg_epochs, Some(f64::from(row.beta)), "{:?}", row);
+            assert!(row.messages.queries > 0, "{:?}", row);
+        }
+        assert_eq!(sweep(&grid).unwrap(), table);
+        assert_eq!(table.to_csv().lines().count(), 1 + table.rows.len());
+    }
+}
@@ -0,0 +1,385 @@
+extern crate avalanche;
+extern crate rand;
+
+use std::sync::Arc;
+
+use rand::Rng;
+
+use avalanche::{
+    keypair_from_seed, seeded_rng, AvalancheError, Behavior, ColorPolicy, ConsensusMode,
+    ConsensusParams, Network, Status, Topology, Transaction, VALID_DATA_LIMIT,
+};
+
+const NODES: u64 = 20;
+const SEED: u64 = 42;
+
+fn transaction(seed: u64, data: i32) -> Transaction {
+    let mut tx = Transaction::random_from(&mut seeded_rng(seed));
+    tx.data = data;
+    tx
+}
+
+/// The status an honest network finalizes the transaction to.
+fn validity(tx: &Transaction) -> Status {
+    match tx.data < VALID_DATA_LIMIT {
+        true => Status::Valid,
+        false => Status::Invalid,
+    }
+}
+
+/// Runs a seeded network on the given transactions until it is quiescent and
+/// asserts every node finalized each of them to `expected`.
+fn assert_finalized(txs: Vec<Transaction>, expected: Status) {
+    let net = Network::with_params_seeded(NODES, ConsensusParams::default(), SEED);
+    let hashes: Vec<_> = txs.iter().map(|tx| tx.id().clone()).collect();
+    for tx in txs {
+        net.inject(tx).unwrap();
+    }
+    net.run_until_quiescent().unwrap();
+    for hash in &hashes {
+        for id in net.node_ids() {
+            let confidence = net.query_confidence(id, hash).unwrap().unwrap();
+            assert!(confidence.is_final, "node {} did not finalize {}", id, hash);
+            assert_eq!(confidence.status, expected, "node {} on {}", id, hash);
+        }
+        assert_eq!(net.network_decision(hash), Some(expected.clone()));
+    }
+    net.check_safety().unwrap();
+}
+
+#[test]
+fn invalid_data_is_rejected_by_every_node() {
+    let txs = (VALID_DATA_LIMIT..VALID_DATA_LIMIT + 3)
+        .map(|data| transaction(data as u64, data))
+        .collect();
+    assert_finalized(txs, Status::Invalid);
+}
+
+#[test]
+fn valid_data_is_accepted_by_every_node() {
+    let txs = (0..VALID_DATA_LIMIT)
+        .map(|data| transaction(data as u64, data))
+        .collect();
+    assert_finalized(txs, Status::Valid);
+}
+
+#[test]
+fn exactly_one_of_two_conflicting_transactions_is_accepted() {
+    let net = Network::with_params_seeded(NODES, ConsensusParams::default(), SEED);
+    let a = transaction(1, 0);
+    let mut b = transaction(2, 0);
+    b.conflict_id = a.conflict_id;
+    let hashes = [a.id().clone(), b.id().clone()];
+    // Both are valid on their own, the nodes each start out preferring the
+    // one they saw first.
+    net.inject_to(0, a).unwrap();
+    net.inject_to(NODES - 1, b).unwrap();
+    net.run_until_quiescent().unwrap();
+
+    let decisions: Vec<_> = hashes
+        .iter()
+        .map(|hash| net.network_decision(hash))
+        .collect();
+    let accepted = decisions
+        .iter()
+        .filter(|d| **d == Some(Status::Valid))
+        .count();
+    let rejected = decisions
+        .iter()
+        .filter(|d| **d == Some(Status::Invalid))
+        .count();
+    assert_eq!((accepted, rejected), (1, 1), "decisions {:?}", decisions);
+    net.check_safety().unwrap();
+}
+
+#[test]
+fn honest_nodes_accept_valid_transactions_despite_a_byzantine_minority() {
+    let net = Network::builder()
+        .nodes(NODES)
+        .seed(SEED)
+        .byzantine_fraction(0.2, Behavior::Flip)
+        .build();
+    let byzantine = net.byzantine_nodes();
+    assert_eq!(byzantine.len(), 4);
+    let honest: Vec<_> = net
+        .node_ids()
+        .into_iter()
+        .filter(|id| !byzantine.contains(id))
+        .collect();
+    let txs: Vec<_> = (0..5).map(|seed| transaction(seed, 0)).collect();
+    let hashes: Vec<_> = txs.iter().map(|tx| tx.id().clone()).collect();
+    for (id, tx) in honest.iter().zip(txs) {
+        net.inject_to(*id, tx).unwrap();
+    }
+    net.run_until_quiescent().unwrap();
+
+    for hash in &hashes {
+        for id in &honest {
+            let confidence = net.query_confidence(*id, hash).unwrap().unwrap();
+            assert!(confidence.is_final, "node {} did not finalize {}", id, hash);
+            assert_eq!(confidence.status, Status::Valid, "node {} on {}", id, hash);
+        }
+    }
+}
+
+/// Runs the network on a valid and an invalid transaction until it is
+/// quiescent and asserts every node finalized both correctly.
+fn assert_valid_and_invalid_finalize(net: Network) {
+    let (valid, invalid) = (transaction(1, 0), transaction(2, VALID_DATA_LIMIT));
+    let hashes = [valid.id().clone(), invalid.id().clone()];
+    net.inject(valid).unwrap();
+    net.inject(invalid).unwrap();
+    net.run_until_quiescent().unwrap();
+    assert_eq!(net.finalized_by(&hashes[0]), NODES as usize);
+    assert_eq!(net.finalized_by(&hashes[1]), NODES as usize);
+    assert_eq!(net.network_decision(&hashes[0]), Some(Status::Valid));
+    assert_eq!(net.network_decision(&hashes[1]), Some(Status::Invalid));
+}
+
+fn assert_mode_finalizes(mode: ConsensusMode) {
+    let params = ConsensusParams {
+        mode,
+        ..ConsensusParams::default()
+    };
+    assert_valid_and_invalid_finalize(Network::with_params_seeded(NODES, params, SEED));
+}
+
+#[test]
+fn snowflake_finalizes() {
+    assert_mode_finalizes(ConsensusMode::Snowflake);
+}
+
+#[test]
+fn snowball_finalizes() {
+    assert_mode_finalizes(ConsensusMode::Snowball);
+}
+
+/// Lets the first three of ten nodes reject what the others accept, each
+/// of them with the given stake, and returns what the network decides.
+fn decision_of_a_minority_with_stake(stake: u64) -> Option<Status> {
+    let net = Network::new_seeded(10, SEED);
+    for id in 0..3 {
+        net.set_stake(id, stake).unwrap();
+        net.set_validator(id, Arc::new(|_: &Transaction| Status::Invalid))
+            .unwrap();
+    }
+    let tx = transaction(1, 0);
+    let hash = tx.id().clone();
+    net.broadcast(tx).unwrap();
+    net.run_until_quiescent().unwrap();
+    net.network_decision(&hash)
+}
+
+#[test]
+fn high_stake_nodes_outweigh_a_low_stake_majority() {
+    assert_eq!(decision_of_a_minority_with_stake(1), Some(Status::Valid));
+    assert_eq!(
+        decision_of_a_minority_with_stake(100),
+        Some(Status::Invalid)
+    );
+}
+
+#[test]
+fn finalized_decisions_are_visible_after_the_network_is_quiescent() {
+    let net = Network::with_params_seeded(NODES, ConsensusParams::default(), SEED);
+    let (valid, invalid) = (transaction(1, 0), transaction(2, VALID_DATA_LIMIT));
+    let hashes = [valid.id().clone(), invalid.id().clone()];
+    assert!(!net.is_finalized(&hashes[0]));
+    net.inject(valid).unwrap();
+    net.inject(invalid).unwrap();
+    net.run_until_quiescent().unwrap();
+
+    let finalized = net.finalized();
+    assert_eq!(finalized[&hashes[0]], Status::Valid);
+    assert_eq!(finalized[&hashes[1]], Status::Invalid);
+    assert!(hashes.iter().all(|hash| net.is_finalized(hash)));
+    assert!(!net.is_finalized(transaction(3, 0).id()));
+}
+
+#[test]
+fn disagreeing_nodes_are_a_safety_violation() {
+    // Two cliques that never sample each other, one of which rejects what
+    // the other accepts.
+    let mut topology = Topology::empty();
+    for clique in &[0..5, 5..10] {
+        for a in clique.clone() {
+            for b in clique.clone() {
+                topology.connect(a, b);
+            }
+        }
+    }
+    let net = Network::builder()
+        .nodes(10)
+        .seed(SEED)
+        .topology(topology)
+        .build();
+    for id in 5..10 {
+        net.set_validator(id, Arc::new(|_: &Transaction| Status::Invalid))
+            .unwrap();
+    }
+    let tx = transaction(1, 0);
+    let hash = tx.id().clone();
+    net.broadcast(tx).unwrap();
+
+    let violation = match net.run_until_quiescent() {
+        Err(AvalancheError::SafetyViolation(violation)) => violation,
+        result => panic!("expected a safety violation, got {:?}", result),
+    };
+    assert_eq!(violation.hash, hash);
+    assert_ne!(violation.first.1, violation.conflicting.1);
+    assert_eq!(net.safety_violations()[0], violation);
+    assert_eq!(
+        net.check_safety(),
+        Err(AvalancheError::SafetyViolation(violation))
+    );
+}
+
+#[test]
+fn signed_transactions_are_accepted_and_tampered_ones_rejected() {
+    let keypair = keypair_from_seed(&[7; 32]);
+    let signed: Vec<_> = (0..3)
+        .map(|seed| transaction(seed, 0).sign(&keypair))
+        .collect();
+    let tampered = signed
+        .iter()
+        .map(|tx| {
+            let mut tx = tx.clone();
+            tx.data += 1;
+            tx
+        })
+        .collect();
+    assert!(signed.iter().all(|tx| tx.verify_signature()));
+    assert_finalized(signed, Status::Valid);
+    assert_finalized(tampered, Status::Invalid);
+}
+
+#[test]
+fn consensus_converges_on_a_sparse_topology() {
+    let ids: Vec<_> = (0..NODES).collect();
+    let topology = Topology::random(&mut seeded_rng(SEED), &ids, 6);
+    assert!(ids
+        .iter()
+        .all(|id| topology.peers(*id).unwrap().len() < ids.len() - 1));
+    let net = Network::builder()
+        .nodes(NODES)
+        .seed(SEED)
+        .topology(topology)
+        .build();
+    assert_valid_and_invalid_finalize(net);
+}
+
+#[test]
+fn concurrent_transactions_finalize_independently() {
+    let net = Network::with_params_seeded(NODES, ConsensusParams::default(), SEED);
+    let txs: Vec<_> = (0..50)
+        .map(|seed| transaction(seed, (seed % 10) as i32))
+        .collect();
+    for (i, tx) in txs.iter().enumerate() {
+        net.inject_to(i as u64 % NODES, tx.clone()).unwrap();
+    }
+    net.run_until_quiescent().unwrap();
+
+    for tx in &txs {
+        assert_eq!(net.finalized_by(tx.id()), NODES as usize, "{}", tx.id());
+        assert_eq!(net.network_decision(tx.id()), Some(validity(tx)));
+    }
+    net.check_safety().unwrap();
+}
+
+/// Property check over random network sizes, transaction mixes and message
+/// orderings, each case derived from its seed so a failure reproduces: no
+/// two nodes disagree (safety) and every transaction is finalized by every
+/// node to its actual validity (liveness, since delivery is synchronous).
+#[test]
+fn random_networks_are_safe_and_live() {
+    for case in 0..50 {
+        let mut rng = seeded_rng(case);
+        let nodes = rng.gen_range(5, 25);
+        let net = Network::with_params_seeded(nodes, ConsensusParams::default(), rng.gen());
+        let txs: Vec<_> = (0..rng.gen_range(1, 10))
+            .map(|_| Transaction::random_from(&mut rng))
+            .collect();
+        for tx in &txs {
+            net.inject_to(rng.gen_range(0, nodes), tx.clone()).unwrap();
+        }
+        net.run_until_quiescent().unwrap();
+
+        net.check_safety().unwrap();
+        for tx in &txs {
+            let finalized = net.finalized_by(tx.id());
+            assert_eq!(finalized, nodes as usize, "case {} tx {}", case, tx.id());
+            let decision = net.network_decision(tx.id());
+            assert_eq!(decision, Some(validity(tx)), "case {}", case);
+        }
+    }
+}
+
+#[test]
+fn honest_disagreement_is_resolved_unanimously() {
+    let net = Network::new_seeded(NODES, SEED);
+    for id in (0..NODES).filter(|id| id % 2 == 0) {
+        net.set_validator(id, Arc::new(|_: &Transaction| Status::Invalid))
+            .unwrap();
+    }
+    let tx = transaction(1, 0);
+    let hash = tx.id().clone();
+    net.broadcast(tx).unwrap();
+    net.run_until_quiescent().unwrap();
+
+    net.check_safety().unwrap();
+    assert_eq!(net.finalized_by(&hash), NODES as usize);
+    let decision = net.finalized()[&hash].clone();
+    for id in net.node_ids() {
+        let confidence = net.query_confidence(id, &hash).unwrap().unwrap();
+        assert_eq!(confidence.status, decision, "node {}", id);
+    }
+}
+
+#[test]
+fn required_quorum_rounds_alpha_times_k_up() {
+    let quorum = |alpha, k| {
+        ConsensusParams {
+            alpha,
+            ..ConsensusParams::default()
+        }
+        .required_quorum(k)
+    };
+    assert_eq!(quorum(0.5, 4), 2);
+    assert_eq!(quorum(0.5, 5), 3);
+    assert_eq!(quorum(0.75, 4), 3);
+    assert_eq!(quorum(0.75, 1), 1);
+    // Exact products must not be pushed up by float rounding.
+    assert_eq!(quorum(0.6, 5), 3);
+    assert_eq!(quorum(0.8, 5), 4);
+    assert_eq!(quorum(0.7, 10), 7);
+    assert_eq!(quorum(1.0, 4), 4);
+}
+
+/// The decision of a network on an invalid transaction that its originator,
+/// node 0, wrongly colors valid.
+fn decision_on_a_wrongly_colored_transaction(policy: ColorPolicy) -> Option<Status> {
+    let params = ConsensusParams {
+        initial_color: policy,
+        ..ConsensusParams::default()
+    };
+    let net = Network::with_params_seeded(NODES, params, SEED);
+    net.set_validator(0, Arc::new(|_: &Transaction| Status::Valid))
+        .unwrap();
+    let tx = transaction(1, VALID_DATA_LIMIT);
+    net.inject_to(0, tx.clone()).unwrap();
+    net.run_until_quiescent().unwrap();
+    assert_eq!(net.finalized_by(tx.id()), NODES as usize);
+    net.network_decision(tx.id())
+}
+
+#[test]
+fn verifying_every_query_keeps_a_wrong_color_from_spreading() {
+    assert_eq!(
+        decision_on_a_wrongly_colored_transaction(ColorPolicy::Adopt),
+        Some(Status::Valid)
+    );
+    assert_eq!(
+        decision_on_a_wrongly_colored_transaction(ColorPolicy::Verify),
+        Some(Status::Invalid)
+    );
+}
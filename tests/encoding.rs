@@ -0,0 +1,68 @@
+extern crate avalanche;
+
+use avalanche::{seeded_rng, AvalancheError, Hash, HashAlgorithm, Transaction};
+
+fn transaction(seed: u64) -> Transaction {
+    Transaction::random_from(&mut seeded_rng(seed))
+}
+
+#[test]
+fn transaction_round_trips_through_its_encoding() {
+    let parent = transaction(1);
+    let tx = transaction(2).with_parents(vec![parent.id().clone()]);
+    let decoded = Transaction::deserialize(&tx.serialize()).unwrap();
+    assert_eq!(decoded, tx);
+    assert_eq!(decoded.id(), tx.id());
+}
+
+#[test]
+fn transactions_differing_only_in_data_hash_differently() {
+    let tx = transaction(1);
+    let mut other = tx.clone();
+    other.data = tx.data + 1;
+    assert_ne!(tx.hash(), other.hash());
+}
+
+#[test]
+fn hash_algorithms_hash_differently_and_stably() {
+    let sha256 = transaction(1).with_hash_algorithm(HashAlgorithm::Sha256);
+    let sha512 = transaction(1).with_hash_algorithm(HashAlgorithm::Sha512_256);
+    assert_ne!(sha256.hash(), sha512.hash());
+    for tx in &[sha256, sha512] {
+        assert_eq!(tx.hash(), tx.hash());
+        let decoded = Transaction::deserialize(&tx.serialize()).unwrap();
+        assert_eq!(decoded.hash(), tx.hash());
+        assert_eq!(decoded.id(), tx.id());
+    }
+}
+
+#[test]
+fn truncated_or_padded_transactions_are_rejected() {
+    let buf = transaction(1).serialize();
+    assert!(Transaction::deserialize(&buf[..buf.len() - 1]).is_err());
+    let mut padded = buf.clone();
+    padded.push(0);
+    assert!(Transaction::deserialize(&padded).is_err());
+}
+
+#[test]
+fn hash_round_trips_through_hex_and_bytes() {
+    let hash = transaction(1).id().clone();
+    assert_eq!(Hash::from_hex(&hash.to_string()).unwrap(), hash);
+    assert_eq!(Hash::from_bytes(hash.as_bytes()).unwrap(), hash);
+}
+
+#[test]
+fn malformed_hashes_are_errors() {
+    let hex = transaction(1).id().to_string();
+    let invalid = |result| match result {
+        Err(AvalancheError::InvalidHash(_)) => {}
+        result => panic!("expected an invalid hash, got {:?}", result),
+    };
+    invalid(Hash::from_bytes(&[0; 31]));
+    invalid(Hash::from_bytes(&[0; 33]));
+    invalid(Hash::from_hex(&hex[..62]));
+    invalid(Hash::from_hex(&hex[..63]));
+    invalid(Hash::from_hex(&format!("{}00", hex)));
+    invalid(Hash::from_hex(&format!("zz{}", &hex[2..])));
+}
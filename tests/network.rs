@@ -0,0 +1,601 @@
+extern crate avalanche;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use avalanche::{
+    keypair_from_seed, seeded_rng, AvalancheError, ConsensusParams, Event, Hash, InvalidReason,
+    Message, Network, NetworkSnapshot, Status, Transaction, DECISION_QUORUM, VALID_DATA_LIMIT,
+};
+
+const SEED: u64 = 7;
+
+fn transaction(seed: u64, data: i32) -> Transaction {
+    let mut tx = Transaction::random_from(&mut seeded_rng(seed));
+    tx.data = data;
+    tx
+}
+
+/// Runs a recorded network of `n` nodes on ten random transactions until it
+/// is quiescent and returns its encoded message log.
+fn recorded_run(n: u64, seed: u64) -> Vec<u8> {
+    let net = Network::builder().nodes(n).seed(seed).record().build();
+    let mut rng = seeded_rng(seed);
+    for _ in 0..10 {
+        net.inject(Transaction::random_from(&mut rng)).unwrap();
+    }
+    net.run_until_quiescent().unwrap();
+    let mut buf = Vec::new();
+    net.message_log().unwrap().write_to(&mut buf).unwrap();
+    buf
+}
+
+#[test]
+fn step_dispatches_a_single_message() {
+    let net = Network::builder().nodes(10).seed(SEED).record().build();
+    net.inject_to(0, transaction(1, 0)).unwrap();
+    assert_eq!(net.message_log().unwrap().len(), 1);
+
+    // The query of node 0 is delivered to its whole sample at once.
+    assert!(net.step().unwrap());
+    let log = net.message_log().unwrap();
+    let query = &log.entries[1..];
+    assert_eq!(query.len(), 4);
+    for entry in query {
+        assert_eq!(entry.origin, 0);
+        assert_eq!(entry.seq, query[0].seq);
+        match entry.msg {
+            Message::Query(_) => {}
+            ref msg => panic!("expected a query, got {:?}", msg),
+        }
+    }
+
+    net.run_until_quiescent().unwrap();
+    assert!(!net.step().unwrap());
+}
+
+#[test]
+fn seeded_runs_are_identical() {
+    assert_eq!(recorded_run(10, SEED), recorded_run(10, SEED));
+    assert_ne!(recorded_run(10, SEED), recorded_run(10, SEED + 1));
+}
+
+/// Decide 20 transactions on the parallel executor and return the decision
+/// of the network and of every node.
+fn parallel_run(seed: u64, threads: usize) -> (HashMap<Hash, Status>, Vec<Vec<Status>>) {
+    let net = Network::new_seeded(20, seed);
+    let txs: Vec<_> = (0..20).map(|i| transaction(i, i as i32 * 3)).collect();
+    for tx in &txs {
+        net.inject(tx.clone()).unwrap();
+    }
+    net.run_until_quiescent_parallel(threads).unwrap();
+    let statuses = net
+        .node_ids()
+        .into_iter()
+        .map(|id| {
+            txs.iter()
+                .map(|tx| net.query_confidence(id, tx.id()).unwrap().unwrap().status)
+                .collect()
+        })
+        .collect();
+    (net.finalized(), statuses)
+}
+
+#[test]
+fn parallel_runs_of_a_seed_are_identical() {
+    let run = parallel_run(SEED, 4);
+    assert_eq!(run.0.len(), 21);
+    assert_eq!(parallel_run(SEED, 4), run);
+    assert_eq!(parallel_run(SEED, 1), run);
+    assert_eq!(parallel_run(SEED, 7), run);
+}
+
+#[test]
+fn node_added_to_a_running_network_takes_part() {
+    let mut net = Network::new_seeded(10, SEED);
+    net.run();
+    let id = net.add_node();
+    assert!(net.node_ids().contains(&id));
+    let tx = transaction(1, 0);
+    let hash = tx.id().clone();
+    net.inject_to(id, tx).unwrap();
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while net.finalized_by(&hash) < net.node_ids().len() {
+        net.health().unwrap();
+        assert!(Instant::now() < deadline, "{} not finalized in time", hash);
+        thread::sleep(Duration::from_millis(10));
+    }
+    let confidence = net.query_confidence(id, &hash).unwrap().unwrap();
+    assert_eq!(
+        (confidence.status, confidence.is_final),
+        (Status::Valid, true)
+    );
+    net.shutdown();
+}
+
+#[test]
+fn mempool_stays_within_its_capacity() {
+    let params = ConsensusParams {
+        mempool_capacity: Some(5),
+        ..ConsensusParams::default()
+    };
+    let net = Network::with_params_seeded(10, params, SEED);
+    let hashes: Vec<_> = (0..20)
+        .map(|seed| {
+            let tx = transaction(seed, 0);
+            let hash = tx.id().clone();
+            net.inject(tx).unwrap();
+            net.run_until_quiescent().unwrap();
+            hash
+        })
+        .collect();
+
+    for id in net.node_ids() {
+        let node = net.node(id).unwrap();
+        // The capacity does not count the genesis.
+        assert!(node.lock().unwrap().mempool_len() <= 6, "node {}", id);
+        let first = net.query_confidence(id, &hashes[0]).unwrap();
+        assert!(first.is_none(), "node {} kept {}", id, hashes[0]);
+        let last = net.query_confidence(id, &hashes[19]).unwrap().unwrap();
+        assert!(last.is_final, "node {} evicted {}", id, hashes[19]);
+    }
+}
+
+#[test]
+fn networks_smaller_than_a_sample_make_progress() {
+    for n in 2..5 {
+        let net = Network::new_seeded(n, SEED);
+        let tx = transaction(1, 0);
+        let hash = tx.id().clone();
+        net.inject(tx).unwrap();
+        net.run_until_quiescent().unwrap();
+        assert_eq!(net.finalized_by(&hash), n as usize, "{} nodes", n);
+        assert_eq!(net.network_decision(&hash), Some(Status::Valid));
+    }
+}
+
+#[test]
+fn run_blocking_returns_once_every_transaction_is_decided() {
+    let mut net = Network::new_seeded(10, SEED);
+    let txs: Vec<_> = (0..10).map(|data| transaction(data as u64, data)).collect();
+    let report = net
+        .run_blocking(txs.clone(), 0.8, Duration::from_secs(10))
+        .unwrap();
+    net.shutdown();
+
+    assert_eq!(report.transactions.len(), txs.len());
+    for tx in &txs {
+        let tx_report = report
+            .transactions
+            .iter()
+            .find(|report| &report.hash == tx.id())
+            .unwrap();
+        let expected = match tx.data < VALID_DATA_LIMIT {
+            true => Status::Valid,
+            false => Status::Invalid,
+        };
+        assert_eq!(tx_report.status, Some(expected));
+        assert!(tx_report.finalized_by >= 8, "{:?}", tx_report);
+    }
+}
+
+#[test]
+fn run_blocking_times_out_without_a_quorum() {
+    let mut net = Network::new_seeded(10, SEED);
+    // Node 0 is cut off from the others, so not all of them can decide.
+    net.partition(&[0], &(1..10).collect::<Vec<_>>());
+    let timeout = Duration::from_millis(200);
+    let result = net.run_blocking(vec![transaction(1, 0)], 1.0, timeout);
+    net.shutdown();
+    assert_eq!(result, Err(AvalancheError::Timeout(timeout)));
+}
+
+#[test]
+fn consensus_resamples_around_slow_nodes() {
+    let mut net = Network::new_seeded(10, SEED);
+    // Far beyond the query timeout and the deadline of the run.
+    for id in 0..3 {
+        net.set_response_delay(id, Duration::from_secs(60)).unwrap();
+    }
+    let tx = transaction(1, 0);
+    let report = net
+        .run_blocking(vec![tx.clone()], 1.0, Duration::from_secs(10))
+        .unwrap();
+    net.shutdown();
+
+    assert_eq!(report.transactions[0].status, Some(Status::Valid));
+    let params = ConsensusParams::default();
+    for id in net.node_ids() {
+        let confidence = net.query_confidence(id, tx.id()).unwrap().unwrap();
+        assert!(confidence.is_final, "node {}", id);
+        assert_eq!(confidence.epoch, params.beta, "node {}", id);
+    }
+}
+
+#[test]
+fn network_decides_once_a_quorum_of_nodes_finalized() {
+    let net = Network::new_seeded(10, SEED);
+    let needed = (DECISION_QUORUM * 10.0).ceil() as usize;
+    let tx = transaction(1, 0);
+    let hash = tx.id().clone();
+    net.inject(tx).unwrap();
+
+    let mut undecided_but_finalized = false;
+    while net.network_decision(&hash).is_none() {
+        assert!(net.finalized_by(&hash) < needed);
+        undecided_but_finalized |= net.is_finalized(&hash);
+        assert!(net.step().unwrap(), "network never decided");
+    }
+    assert!(
+        undecided_but_finalized,
+        "a single node decided for the network"
+    );
+    assert!(net.finalized_by(&hash) >= needed);
+    assert_eq!(net.network_decision(&hash), Some(Status::Valid));
+}
+
+#[test]
+fn injecting_hands_out_the_decision_of_the_network() {
+    let mut net = Network::new_seeded(10, SEED);
+    let valid = net.inject(transaction(1, 0)).unwrap();
+    let invalid = net.inject(transaction(2, VALID_DATA_LIMIT)).unwrap();
+    assert_eq!(valid.try_wait(), Ok(None));
+    net.run();
+    let timeout = Duration::from_secs(10);
+    let decided = (valid.wait_timeout(timeout), invalid.wait_timeout(timeout));
+    net.shutdown();
+    assert_eq!(decided, (Ok(Status::Valid), Ok(Status::Invalid)));
+    assert_eq!(net.network_decision(valid.hash()), Some(Status::Valid));
+}
+
+#[test]
+fn stats_sum_up_a_known_run() {
+    let net = Network::new_seeded(10, SEED);
+    let stats = net.stats();
+    assert_eq!((stats.finalized, stats.avg_epochs), (0, None));
+    assert_eq!(stats.messages.total(), 0);
+
+    net.inject(transaction(1, 0)).unwrap();
+    net.inject(transaction(2, VALID_DATA_LIMIT)).unwrap();
+    net.run_until_quiescent().unwrap();
+    let stats = net.stats();
+    assert_eq!(stats.finalized, 2);
+    // Every node decides on its own, taking beta epochs.
+    let beta = ConsensusParams::default().beta;
+    assert_eq!(stats.avg_epochs, Some(f64::from(beta)));
+    assert_eq!(stats.messages, net.message_stats().total());
+    assert!(stats.messages.queries > 0 && stats.messages.responses > 0);
+    assert_eq!(stats.dropped_messages, 0);
+    // The genesis and both transactions.
+    assert!(stats.mempool_sizes.values().all(|len| *len == 3));
+    assert_eq!(stats.mempool_sizes.len(), 10);
+    assert!(stats.safety_violations.is_empty());
+}
+
+#[test]
+fn sequence_numbers_increase_per_sender() {
+    let net = Network::builder().nodes(10).seed(SEED).record().build();
+    for seed in 0..5 {
+        net.inject(transaction(seed, 0)).unwrap();
+    }
+    net.run_until_quiescent().unwrap();
+
+    // Every node's messages arrive in the order it sent them. A query is
+    // delivered to each sampled peer under the same number, which no other
+    // message carries.
+    let (mut last, mut senders) = (HashMap::new(), HashMap::new());
+    let log = net.message_log().unwrap();
+    for entry in &log.entries {
+        assert_eq!(
+            *senders.entry(entry.seq).or_insert(entry.origin),
+            entry.origin
+        );
+        if let Some(&(seq, to)) = last.get(&entry.origin) {
+            assert!(
+                entry.seq > seq || (entry.seq == seq && entry.to != to),
+                "node {} sent {} after {}",
+                entry.origin,
+                entry.seq,
+                seq
+            );
+        }
+        last.insert(entry.origin, (entry.seq, entry.to));
+    }
+}
+
+#[test]
+fn late_joiner_adopts_a_shared_decision() {
+    let params = ConsensusParams {
+        share_decisions: true,
+        ..ConsensusParams::default()
+    };
+    let net = Network::with_params_seeded(10, params, SEED);
+    let tx = transaction(1, 0);
+    let hash = tx.id().clone();
+    net.inject(tx.clone()).unwrap();
+    net.run_until_quiescent().unwrap();
+    assert_eq!(net.finalized_by(&hash), 10);
+
+    // The joined node learns of the transaction and queries its peers, which
+    // answer with their decision.
+    let id = net.add_node();
+    net.inject_to(id, tx).unwrap();
+    net.run_until_quiescent().unwrap();
+    let confidence = net.query_confidence(id, &hash).unwrap().unwrap();
+    assert_eq!(
+        (confidence.status, confidence.is_final),
+        (Status::Valid, true)
+    );
+    assert_eq!(confidence.epoch, 0);
+}
+
+#[test]
+fn joined_node_pulls_and_decides_a_transaction_in_progress() {
+    let net = Network::new_seeded(10, SEED);
+    let tx = transaction(1, 0);
+    let hash = tx.id().clone();
+    net.inject(tx).unwrap();
+    for _ in 0..20 {
+        assert!(net.step().unwrap());
+    }
+    assert!(!net.is_finalized(&hash));
+
+    // Nobody hands the transaction to the joined node, it has to pull it.
+    let id = net.add_node();
+    net.run_until_quiescent().unwrap();
+    let confidence = net.query_confidence(id, &hash).unwrap().unwrap();
+    assert_eq!(
+        (confidence.status, confidence.is_final),
+        (Status::Valid, true)
+    );
+    assert_eq!(net.finalized_by(&hash), 11);
+}
+
+#[test]
+fn vote_of_a_node_that_left_is_excluded_from_the_quorum() {
+    let net = Network::new_seeded(10, SEED);
+    let tx = transaction(1, 0);
+    let hash = tx.id().clone();
+    net.inject_to(0, tx).unwrap();
+    let node = net.node(0).unwrap();
+    let responses = || {
+        let node = node.lock().unwrap();
+        let state = node.tx_state(&hash).unwrap();
+        (state.round(), state.epoch_responses().clone())
+    };
+    // Deliver messages until the first response of the sample of node 0.
+    while responses().1.is_empty() {
+        assert!(net.step().unwrap(), "node 0 never got a response");
+    }
+    let (round, votes) = responses();
+    let left = *votes.keys().next().unwrap();
+    net.remove_node(left).unwrap();
+    assert!(!responses().1.contains_key(&left));
+
+    // The round can't be decided on the remaining responses alone.
+    net.run_until_quiescent().unwrap();
+    let (now, votes) = responses();
+    assert_eq!(now, round);
+    assert!(!votes.contains_key(&left));
+}
+
+#[test]
+fn consensus_stalls_in_a_partition_and_resumes_once_healed() {
+    let net = Network::new_seeded(10, SEED);
+    net.partition(&[0, 1, 2, 3, 4], &[5, 6, 7, 8, 9]);
+    let tx = transaction(1, 0);
+    let hash = tx.id().clone();
+    net.inject_to(0, tx).unwrap();
+    net.run_until_quiescent().unwrap();
+    assert_eq!(net.finalized_by(&hash), 0);
+    net.check_safety().unwrap();
+
+    net.heal().unwrap();
+    net.run_until_quiescent().unwrap();
+    assert_eq!(net.finalized_by(&hash), 10);
+    assert_eq!(net.network_decision(&hash), Some(Status::Valid));
+}
+
+#[test]
+fn every_node_starts_with_the_final_genesis() {
+    let genesis = Transaction::genesis();
+    assert!(genesis.is_genesis());
+    assert_eq!(genesis.hash(), *Transaction::genesis().id());
+    let net = Network::new_seeded(5, SEED);
+    for id in net.node_ids() {
+        let confidence = net.query_confidence(id, genesis.id()).unwrap().unwrap();
+        assert_eq!(
+            (confidence.status, confidence.is_final),
+            (Status::Valid, true),
+            "node {}",
+            id
+        );
+    }
+    assert_eq!(net.finalized().get(genesis.id()), Some(&Status::Valid));
+}
+
+#[test]
+fn single_node_network_never_finalizes_on_an_empty_sample() {
+    let net = Network::new_seeded(1, SEED);
+    let tx = transaction(1, 0);
+    let hash = tx.id().clone();
+    net.inject(tx).unwrap();
+    net.run_until_quiescent().unwrap();
+    let confidence = net.query_confidence(0, &hash).unwrap().unwrap();
+    assert!(!confidence.is_final);
+    assert_eq!((confidence.epoch, confidence.cnt), (0, 0));
+    assert!(!net.is_finalized(&hash));
+    assert_eq!(net.network_decision(&hash), None);
+}
+
+#[test]
+fn restored_snapshot_keeps_every_decision() {
+    let net = Network::new_seeded(10, SEED);
+    let txs: Vec<_> = (0..5)
+        .map(|data| transaction(data as u64, data * 2))
+        .collect();
+    for tx in &txs {
+        net.inject(tx.clone()).unwrap();
+    }
+    net.run_until_quiescent().unwrap();
+    let mut buf = Vec::new();
+    net.snapshot().write_to(&mut buf).unwrap();
+    let restored = Network::restore(&NetworkSnapshot::read_from(&mut &buf[..]).unwrap());
+
+    assert_eq!(restored.finalized(), net.finalized());
+    assert_eq!(restored.node_ids(), net.node_ids());
+    for id in net.node_ids() {
+        for tx in &txs {
+            let confidence = net.query_confidence(id, tx.id()).unwrap();
+            assert_eq!(restored.query_confidence(id, tx.id()).unwrap(), confidence);
+        }
+    }
+    // The restored network keeps deciding.
+    let tx = transaction(9, 0);
+    let hash = tx.id().clone();
+    restored.inject(tx).unwrap();
+    restored.run_until_quiescent().unwrap();
+    assert_eq!(restored.network_decision(&hash), Some(Status::Valid));
+}
+
+#[test]
+fn accepted_and_rejected_events_fire_for_every_node() {
+    let net = Network::new_seeded(10, SEED);
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let observed = events.clone();
+    net.on_event(move |event| match *event {
+        Event::Accepted { node, ref tx } => observed.lock().unwrap().push((node, tx.clone(), true)),
+        Event::Rejected { node, ref tx, .. } => {
+            observed.lock().unwrap().push((node, tx.clone(), false))
+        }
+        _ => {}
+    });
+    let (valid, invalid) = (transaction(1, 0), transaction(2, VALID_DATA_LIMIT));
+    net.inject(valid.clone()).unwrap();
+    net.inject(invalid.clone()).unwrap();
+    net.run_until_quiescent().unwrap();
+
+    let events = events.lock().unwrap();
+    assert_eq!(events.len(), 20);
+    for (tx, accepted) in &[(valid, true), (invalid, false)] {
+        let mut nodes: Vec<_> = events
+            .iter()
+            .filter(|event| event.1 == *tx)
+            .map(|event| {
+                assert_eq!(event.2, *accepted, "node {} on {}", event.0, tx.id());
+                event.0
+            })
+            .collect();
+        nodes.sort();
+        assert_eq!(nodes, net.node_ids());
+    }
+}
+
+/// Collect the `Accepted` and `Rejected` events of every node of `net`.
+fn outcomes(net: &Network) -> Arc<Mutex<Vec<Event>>> {
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let observed = events.clone();
+    net.on_event(move |event| match *event {
+        Event::Accepted { .. } | Event::Rejected { .. } => {
+            observed.lock().unwrap().push(event.clone())
+        }
+        _ => {}
+    });
+    events
+}
+
+#[test]
+fn rejected_events_carry_the_reason() {
+    let net = Network::new_seeded(10, SEED);
+    let events = outcomes(&net);
+    let mut tampered = transaction(3, 0).sign(&keypair_from_seed(&[7; 32]));
+    tampered.data += 1;
+    let cases = [
+        (
+            transaction(2, VALID_DATA_LIMIT),
+            InvalidReason::DataOverLimit,
+        ),
+        (tampered, InvalidReason::BadSignature),
+    ];
+    for (tx, _) in &cases {
+        net.broadcast(tx.clone()).unwrap();
+    }
+    net.run_until_quiescent().unwrap();
+
+    let events = events.lock().unwrap();
+    assert_eq!(events.len(), 2 * 10);
+    for (tx, expected) in &cases {
+        let mut nodes = Vec::new();
+        for event in events.iter() {
+            match *event {
+                Event::Rejected {
+                    node,
+                    tx: ref rejected,
+                    reason,
+                } if rejected == tx => {
+                    assert_eq!(reason, Some(*expected), "node {}", node);
+                    nodes.push(node);
+                }
+                Event::Rejected { .. } => {}
+                ref event => panic!("expected a rejection, got {:?}", event),
+            }
+        }
+        nodes.sort();
+        assert_eq!(nodes, net.node_ids());
+    }
+}
+
+#[test]
+fn reason_does_not_leak_into_an_accepted_outcome() {
+    let net = Network::new_seeded(10, SEED);
+    let events = outcomes(&net);
+    // Node 0 rejects the valid transaction and accepts the invalid one, the
+    // other nodes outvote it on both.
+    let (valid, invalid) = (transaction(1, 0), transaction(2, VALID_DATA_LIMIT));
+    let rejected = valid.id().clone();
+    net.set_validator(
+        0,
+        Arc::new(move |tx: &Transaction| match *tx.id() == rejected {
+            true => Status::Invalid,
+            false => Status::Valid,
+        }),
+    )
+    .unwrap();
+    net.broadcast(valid.clone()).unwrap();
+    net.broadcast(invalid.clone()).unwrap();
+    let node = net.node(0).unwrap();
+    let reason = |tx: &Transaction| {
+        let node = node.lock().unwrap();
+        node.tx_state(tx.id()).unwrap().invalid_reason()
+    };
+    assert_eq!(reason(&valid), Some(InvalidReason::Validator));
+    assert_eq!(reason(&invalid), None);
+    net.run_until_quiescent().unwrap();
+
+    let events: Vec<_> = events
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|event| match **event {
+            Event::Accepted { node, .. } | Event::Rejected { node, .. } => node == 0,
+            _ => false,
+        })
+        .cloned()
+        .collect();
+    // Accepting carries no reason, rejecting after following the peers
+    // carries none either.
+    assert_eq!(
+        events,
+        vec![
+            Event::Accepted { node: 0, tx: valid },
+            Event::Rejected {
+                node: 0,
+                tx: invalid,
+                reason: None,
+            },
+        ]
+    );
+}
@@ -0,0 +1,410 @@
+extern crate avalanche;
+
+use std::sync::mpsc::channel;
+
+use avalanche::{
+    keypair_from_seed, seeded_rng, AvalancheError, ConsensusParams, Hash, InvalidReason, Message,
+    Node, QueryResponse, Status, Transaction, TxState, VALID_DATA_LIMIT,
+};
+
+fn transaction(seed: u64, data: i32) -> Transaction {
+    let mut tx = Transaction::random_from(&mut seeded_rng(seed));
+    tx.data = data;
+    tx
+}
+
+#[test]
+fn send_to_a_closed_network_is_an_error() {
+    let (sender, receiver) = channel();
+    let mut node = Node::new_with_mempool(0, sender, ConsensusParams::default(), Vec::new());
+    drop(receiver);
+    let msg = Message::Transaction(transaction(1, 0));
+    assert_eq!(
+        node.handle_message(1, &msg),
+        Err(AvalancheError::Disconnected)
+    );
+}
+
+/// Let `k` peers respond to the current round of `node` on `hash` with
+/// `status`, which completes the round.
+fn quorum(node: &mut Node, hash: &Hash, status: Status) {
+    let k = ConsensusParams::default().k as u64;
+    let round = node.tx_state(hash).unwrap().round();
+    for peer in 1..=k {
+        let resp = QueryResponse::new(hash.clone(), status.clone(), 1, k).with_round(round);
+        node.handle_message(peer, &Message::QueryResponse((0, resp)))
+            .unwrap();
+    }
+}
+
+#[test]
+fn accepting_the_tip_of_a_chain_accepts_the_whole_chain() {
+    let params = ConsensusParams::default();
+    let mut parent = Transaction::genesis().id().clone();
+    let mut chain = Vec::new();
+    for seed in 0..10 {
+        let tx = transaction(seed, 0).with_parents(vec![parent]);
+        parent = tx.id().clone();
+        chain.push(TxState::new(tx, Status::Valid, params));
+    }
+    let hashes: Vec<_> = chain.iter().map(|state| state.tx().id().clone()).collect();
+    let (sender, _receiver) = channel();
+    let mut node = Node::new_with_mempool(0, sender, params, chain);
+
+    let tip = hashes.last().unwrap();
+    let mut rounds = 0;
+    while !node.tx_state(tip).unwrap().is_final() {
+        quorum(&mut node, tip, Status::Valid);
+        rounds += 1;
+        assert!(rounds <= 20, "tip not final after {} rounds", rounds);
+    }
+    for hash in &hashes {
+        let state = node.tx_state(hash).unwrap();
+        assert!(state.is_final(), "ancestor {} not final", hash);
+        assert_eq!(state.status(), &Status::Valid);
+    }
+}
+
+#[test]
+fn response_for_an_unknown_transaction_is_dropped() {
+    let (sender, receiver) = channel();
+    let mut node = Node::new_with_mempool(0, sender, ConsensusParams::default(), Vec::new());
+    let hash = transaction(1, 0).id().clone();
+    let resp = QueryResponse::new(hash.clone(), Status::Valid, 1, 4);
+    node.handle_message(1, &Message::QueryResponse((0, resp)))
+        .unwrap();
+    assert!(node.tx_state(&hash).is_none());
+    assert!(receiver.try_recv().is_err());
+}
+
+#[test]
+fn duplicate_responses_count_once() {
+    let params = ConsensusParams::default();
+    let tx = transaction(1, 0);
+    let hash = tx.id().clone();
+    let (sender, _receiver) = channel();
+    let state = TxState::new(tx, Status::Valid, params);
+    let mut node = Node::new_with_mempool(0, sender, params, vec![state]);
+    for _ in 0..params.k {
+        let resp = QueryResponse::new(hash.clone(), Status::Valid, 1, params.k as u64);
+        node.handle_message(1, &Message::QueryResponse((0, resp)))
+            .unwrap();
+    }
+    let state = node.tx_state(&hash).unwrap();
+    assert_eq!(state.epoch_responses().len(), 1);
+    assert_eq!(state.cnt(), 0);
+}
+
+#[test]
+fn a_new_query_is_sent_once_per_round() {
+    let params = ConsensusParams::default();
+    let tx = transaction(1, 0);
+    let hash = tx.id().clone();
+    let (sender, receiver) = channel();
+    let state = TxState::new(tx, Status::Valid, params);
+    let mut node = Node::new_with_mempool(0, sender, params, vec![state]);
+    for peer in 1..params.k as u64 {
+        let resp = QueryResponse::new(hash.clone(), Status::Valid, 1, params.k as u64);
+        node.handle_message(peer, &Message::QueryResponse((0, resp)))
+            .unwrap();
+        assert!(
+            receiver.try_recv().is_err(),
+            "query after {} responses",
+            peer
+        );
+    }
+    let resp = QueryResponse::new(hash.clone(), Status::Valid, 1, params.k as u64);
+    node.handle_message(params.k as u64, &Message::QueryResponse((0, resp)))
+        .unwrap();
+    let sent: Vec<_> = receiver.try_iter().collect();
+    assert_eq!(sent.len(), 1, "sent {:?}", sent);
+    match sent[0].msg {
+        Message::Query(_) => {}
+        ref msg => panic!("expected a query, got {:?}", msg),
+    }
+}
+
+/// A valid transaction one quorum short of its last epoch.
+fn almost_final(tx: Transaction, params: ConsensusParams) -> TxState {
+    TxState::new(tx, Status::Valid, params)
+        .with_epoch(params.beta - 1)
+        .with_confidence(3, 0)
+        .with_cnt(3)
+}
+
+#[test]
+fn preloaded_state_finalizes_on_the_next_accepted_epoch() {
+    let params = ConsensusParams::default();
+    let tx = transaction(1, 0);
+    let hash = tx.id().clone();
+    let state = almost_final(tx, params);
+    let (sender, _receiver) = channel();
+    let mut node = Node::new_with_mempool(0, sender, params, vec![state]);
+    let confidence = node.query_confidence(&hash).unwrap();
+    assert_eq!((confidence.epoch, confidence.cnt), (params.beta - 1, 3));
+    assert!(!confidence.is_final);
+
+    quorum(&mut node, &hash, Status::Valid);
+    let confidence = node.query_confidence(&hash).unwrap();
+    assert_eq!((confidence.epoch, confidence.cnt), (params.beta, 4));
+    assert!(confidence.is_final);
+}
+
+#[test]
+fn finalized_node_answers_queries_without_querying_itself() {
+    let params = ConsensusParams::default();
+    let tx = transaction(1, 0);
+    let hash = tx.id().clone();
+    let (sender, receiver) = channel();
+    let state = almost_final(tx.clone(), params);
+    let mut node = Node::new_with_mempool(0, sender, params, vec![state]);
+    quorum(&mut node, &hash, Status::Valid);
+    assert!(node.tx_state(&hash).unwrap().is_final());
+    // Forget what finalizing sent.
+    receiver.try_iter().count();
+
+    // Another node still deciding on the transaction queries us.
+    let (peer_sender, peer_receiver) = channel();
+    let state = TxState::new(tx, Status::Valid, params);
+    let mut peer = Node::new_with_mempool(1, peer_sender, params, vec![state]);
+    quorum(&mut peer, &hash, Status::Valid);
+    let query = peer_receiver.try_recv().unwrap().msg;
+    node.handle_message(1, &query).unwrap();
+
+    let sent: Vec<_> = receiver.try_iter().collect();
+    assert_eq!(sent.len(), 1, "sent {:?}", sent);
+    match sent[0].msg {
+        Message::QueryResponse((1, _)) => {}
+        ref msg => panic!("expected a response, got {:?}", msg),
+    }
+}
+
+#[test]
+fn finalizing_takes_at_least_beta_epochs() {
+    let params = ConsensusParams::default();
+    let tx = transaction(1, 0);
+    let hash = tx.id().clone();
+    let (sender, _receiver) = channel();
+    let state = TxState::new(tx, Status::Valid, params);
+    let mut node = Node::new_with_mempool(0, sender, params, vec![state]);
+
+    let mut rounds = 0;
+    while !node.tx_state(&hash).unwrap().is_final() {
+        quorum(&mut node, &hash, Status::Valid);
+        rounds += 1;
+        assert!(rounds <= 20, "not final after {} rounds", rounds);
+    }
+    // Three quorums build the conviction, then each one accepts an epoch.
+    assert_eq!(rounds, 3 + params.beta);
+    assert_eq!(node.tx_state(&hash).unwrap().epoch(), params.beta);
+}
+
+#[test]
+fn peeking_at_validity_sends_nothing_and_keeps_the_mempool() {
+    let (sender, receiver) = channel();
+    let node = Node::new_with_mempool(0, sender, ConsensusParams::default(), Vec::new());
+    let len = node.mempool_len();
+    assert_eq!(node.peek_validity(&transaction(1, 0)), Status::Valid);
+    let invalid = transaction(2, VALID_DATA_LIMIT);
+    assert_eq!(node.peek_validity(&invalid), Status::Invalid);
+    assert_eq!(node.mempool_len(), len);
+    assert!(node.tx_state(invalid.id()).is_none());
+    assert!(receiver.try_recv().is_err());
+}
+
+#[test]
+fn eviction_keeps_high_priority_transactions() {
+    let params = ConsensusParams {
+        mempool_capacity: Some(3),
+        ..ConsensusParams::default()
+    };
+    let (sender, _receiver) = channel();
+    let mut node = Node::new_with_mempool(0, sender, params, Vec::new());
+    let txs: Vec<_> = [10, 1, 20, 5, 30]
+        .iter()
+        .enumerate()
+        .map(|(seed, priority)| transaction(seed as u64, 0).with_priority(*priority))
+        .collect();
+    for tx in &txs {
+        node.handle_message(1, &Message::Transaction(tx.clone()))
+            .unwrap();
+    }
+    for tx in &txs {
+        let kept = node.tx_state(tx.id()).is_some();
+        assert_eq!(kept, tx.priority >= 10, "priority {}", tx.priority);
+    }
+}
+
+#[test]
+fn injecting_a_known_transaction_again_keeps_its_progress() {
+    let params = ConsensusParams::default();
+    let tx = transaction(1, 0);
+    let hash = tx.id().clone();
+    let (sender, _receiver) = channel();
+    let mut node = Node::new_with_mempool(0, sender, params, Vec::new());
+    node.handle_message(0, &Message::Transaction(tx.clone()))
+        .unwrap();
+    quorum(&mut node, &hash, Status::Valid);
+    quorum(&mut node, &hash, Status::Valid);
+    let before = node.query_confidence(&hash).unwrap();
+    let round = node.tx_state(&hash).unwrap().round();
+    assert_eq!(before.cnt, 2);
+
+    node.handle_message(0, &Message::Transaction(tx)).unwrap();
+    assert_eq!(node.query_confidence(&hash).unwrap(), before);
+    assert_eq!(node.tx_state(&hash).unwrap().round(), round);
+}
+
+#[test]
+fn responses_of_a_past_round_do_not_count_in_the_next() {
+    let params = ConsensusParams::default();
+    let k = params.k as u64;
+    let tx = transaction(1, 0);
+    let hash = tx.id().clone();
+    let (sender, _receiver) = channel();
+    let state = TxState::new(tx, Status::Valid, params);
+    let mut node = Node::new_with_mempool(0, sender, params, vec![state]);
+    let past = node.tx_state(&hash).unwrap().round();
+    quorum(&mut node, &hash, Status::Valid);
+    let state = node.tx_state(&hash).unwrap();
+    assert!(state.epoch_responses().is_empty());
+    assert_eq!((state.round(), state.cnt()), (past + 1, 1));
+
+    // A late response to the past round, then all but one for the current.
+    let late = QueryResponse::new(hash.clone(), Status::Valid, 1, k).with_round(past);
+    node.handle_message(k + 1, &Message::QueryResponse((0, late)))
+        .unwrap();
+    assert!(node.tx_state(&hash).unwrap().epoch_responses().is_empty());
+    for peer in 1..k {
+        let resp = QueryResponse::new(hash.clone(), Status::Valid, 1, k).with_round(past + 1);
+        node.handle_message(peer, &Message::QueryResponse((0, resp)))
+            .unwrap();
+    }
+    let state = node.tx_state(&hash).unwrap();
+    assert_eq!(state.epoch_responses().len(), k as usize - 1);
+    assert_eq!(state.cnt(), 1);
+}
+
+#[test]
+fn a_sample_smaller_than_k_decides_on_its_own_stake() {
+    let params = ConsensusParams::default();
+    let tx = transaction(1, 0);
+    let hash = tx.id().clone();
+    let (sender, receiver) = channel();
+    let state = TxState::new(tx, Status::Valid, params);
+    let mut node = Node::new_with_mempool(0, sender, params, vec![state]);
+    let round = node.tx_state(&hash).unwrap().round();
+
+    // Only two nodes could be sampled, both of them answer Valid.
+    for peer in 1..=2 {
+        let resp = QueryResponse::new(hash.clone(), Status::Valid, 1, 2).with_round(round);
+        node.handle_message(peer, &Message::QueryResponse((0, resp)))
+            .unwrap();
+    }
+    let state = node.tx_state(&hash).unwrap();
+    assert_eq!((state.round(), state.cnt()), (round + 1, 1));
+    assert_eq!(receiver.try_iter().count(), 1);
+
+    // A split sample of two misses the quorum and counts for nothing.
+    let statuses = [Status::Valid, Status::Invalid];
+    for (peer, status) in statuses.iter().enumerate() {
+        let resp = QueryResponse::new(hash.clone(), status.clone(), 1, 2).with_round(round + 1);
+        node.handle_message(peer as u64 + 1, &Message::QueryResponse((0, resp)))
+            .unwrap();
+    }
+    let state = node.tx_state(&hash).unwrap();
+    assert_eq!((state.round(), state.cnt()), (round + 2, 1));
+}
+
+#[test]
+fn decided_transactions_yields_only_finalized_ones() {
+    let params = ConsensusParams::default();
+    let valid = transaction(1, 0);
+    let invalid = transaction(2, VALID_DATA_LIMIT);
+    let pending = transaction(3, 0);
+    let (sender, _receiver) = channel();
+    let states = vec![
+        almost_final(valid.clone(), params),
+        TxState::new(invalid.clone(), Status::Invalid, params)
+            .with_epoch(params.beta - 1)
+            .with_confidence(0, 3)
+            .with_cnt(3),
+        TxState::new(pending.clone(), Status::Valid, params),
+    ];
+    let mut node = Node::new_with_mempool(0, sender, params, states);
+    assert_eq!(node.decided_transactions().count(), 0);
+
+    quorum(&mut node, valid.id(), Status::Valid);
+    quorum(&mut node, invalid.id(), Status::Invalid);
+    quorum(&mut node, pending.id(), Status::Valid);
+    let mut decided: Vec<_> = node
+        .decided_transactions()
+        .map(|(hash, status)| (hash.clone(), status.clone()))
+        .collect();
+    decided.sort_by_key(|(_, status)| *status == Status::Invalid);
+    assert_eq!(
+        decided,
+        vec![
+            (valid.id().clone(), Status::Valid),
+            (invalid.id().clone(), Status::Invalid),
+        ]
+    );
+}
+
+#[test]
+fn rejected_transactions_carry_the_reason() {
+    let (sender, _receiver) = channel();
+    let mut node = Node::new_with_mempool(0, sender, ConsensusParams::default(), Vec::new());
+    let mut tampered = transaction(3, 0).sign(&keypair_from_seed(&[7; 32]));
+    tampered.data += 1;
+    let cases = vec![
+        (transaction(1, 0), None),
+        (
+            transaction(2, VALID_DATA_LIMIT),
+            Some(InvalidReason::DataOverLimit),
+        ),
+        (tampered, Some(InvalidReason::BadSignature)),
+    ];
+    for (tx, reason) in cases {
+        node.handle_message(0, &Message::Transaction(tx.clone()))
+            .unwrap();
+        let state = node.tx_state(tx.id()).unwrap();
+        assert_eq!(state.invalid_reason(), reason, "data {}", tx.data);
+        let status = match reason {
+            Some(_) => Status::Invalid,
+            None => Status::Valid,
+        };
+        assert_eq!(state.status(), &status);
+    }
+}
+
+#[test]
+fn a_fresh_node_queries_a_new_transaction_once() {
+    let params = ConsensusParams::default();
+    let tx = transaction(1, 0);
+    let hash = tx.id().clone();
+    let (peer_sender, peer_receiver) = channel();
+    let state = TxState::new(tx, Status::Valid, params);
+    let mut peer = Node::new_with_mempool(1, peer_sender, params, vec![state]);
+    quorum(&mut peer, &hash, Status::Valid);
+    let query = peer_receiver.try_recv().unwrap().msg;
+
+    let (sender, receiver) = channel();
+    let mut node = Node::new_with_mempool(0, sender, params, Vec::new());
+    node.handle_message(1, &query).unwrap();
+    let sent: Vec<_> = receiver.try_iter().map(|envelope| envelope.msg).collect();
+    let queries = sent
+        .iter()
+        .filter(|msg| matches!(msg, Message::Query(_)))
+        .count();
+    assert_eq!((sent.len(), queries), (2, 1), "sent {:?}", sent);
+
+    // The same query again, e.g. from another peer, is only answered.
+    node.handle_message(2, &query).unwrap();
+    let sent: Vec<_> = receiver.try_iter().collect();
+    assert_eq!(sent.len(), 1, "sent {:?}", sent);
+    match sent[0].msg {
+        Message::QueryResponse((2, _)) => {}
+        ref msg => panic!("expected a response, got {:?}", msg),
+    }
+}
@@ -0,0 +1,28 @@
+extern crate avalanche;
+
+use avalanche::{seeded_rng, Candidate, SamplingStrategy, StakeWeighted, Uniform};
+
+/// Fraction of `rounds` single node samples of `strategy` that picked node 0,
+/// which has ten times the stake of each of the other nine.
+fn share_of_high_stake_node(strategy: &dyn SamplingStrategy, rounds: usize) -> f64 {
+    let candidates: Vec<_> = (0..10)
+        .map(|id| Candidate {
+            id,
+            stake: if id == 0 { 10 } else { 1 },
+        })
+        .collect();
+    let mut rng = seeded_rng(1);
+    let picked = (0..rounds)
+        .filter(|_| strategy.sample(&mut rng, 99, &candidates, 1, &[]) == vec![0])
+        .count();
+    picked as f64 / rounds as f64
+}
+
+#[test]
+fn stake_weighted_sampling_favors_high_stake_nodes() {
+    // Node 0 holds 10 of the 19 stake but is one of ten nodes.
+    let weighted = share_of_high_stake_node(&StakeWeighted, 10_000);
+    assert!((weighted - 10.0 / 19.0).abs() < 0.03, "{}", weighted);
+    let uniform = share_of_high_stake_node(&Uniform, 10_000);
+    assert!((uniform - 0.1).abs() < 0.03, "{}", uniform);
+}